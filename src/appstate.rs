@@ -1,21 +1,53 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-use enigo::{Enigo, KeyboardControllable};
+use crate::backend::{EnigoBackend, KeyBackend};
+use crate::chords::ChordState;
+use crate::gestures::GestureState;
+use crate::latch::NoteModLatch;
+use crate::midi::MidiNote;
+use crate::notemappings::{ContinuousSource, KbdKey, MouseButton, NoteMappings};
+use crate::scheduler::{Scheduler, Source};
+use crate::transpose::Transposer;
 
-thread_local!(static ENIGO: RefCell<Enigo> = RefCell::new(Default::default()));
+/// What's known about a key while it's held down.
+struct HeldKey {
+    /// When the key was pressed
+    since: Instant,
 
-use crate::notemappings::{KbdKey, NoteMappings};
+    /// The device that pressed the key, where known
+    device: Option<String>,
 
-#[derive(Default)]
-pub struct KeyGen {
+    /// How long the key may be held before it's considered stuck
+    max_hold: Option<Duration>,
+}
+
+/// Tracks which keys and mouse buttons are held, and presses and releases
+/// them through a backend.  By default the backend is chosen at runtime.
+pub struct KeyGen<B: KeyBackend = Box<dyn KeyBackend + Send>> {
+    backend: B,
     key_state: HashMap<KbdKey, bool>,
+    held: HashMap<KbdKey, HeldKey>,
+
+    /// The mouse buttons that are held, and the device that pressed each
+    buttons: HashMap<MouseButton, String>,
+}
+
+impl Default for KeyGen {
+    fn default() -> KeyGen {
+        KeyGen::new(Box::new(EnigoBackend))
+    }
 }
 
-impl KeyGen {
-    pub fn new() -> KeyGen {
-        KeyGen::default()
+impl<B: KeyBackend> KeyGen<B> {
+    pub fn new(backend: B) -> KeyGen<B> {
+        KeyGen {
+            backend,
+            key_state: HashMap::new(),
+            held: HashMap::new(),
+            buttons: HashMap::new(),
+        }
     }
 
     /// Press a given key.
@@ -27,43 +59,243 @@ impl KeyGen {
             }
         }
         self.key_state.insert(key.clone(), true);
-        ENIGO.with(|enigo| enigo.borrow_mut().key_down(KbdKey::to_enigo_key(key)));
+        self.held.insert(
+            key.clone(),
+            HeldKey {
+                since: Instant::now(),
+                device: None,
+                max_hold: None,
+            },
+        );
+        self.backend.key_down(key);
+        true
+    }
+
+    /// Press a given key on behalf of `device`, so it can be released if
+    /// the device goes away.  If `max_hold` is set, the key is considered
+    /// stuck once it has been held for longer than that.
+    /// Returns `true` if an event was sent.
+    pub fn key_down_from(
+        &mut self,
+        key: &KbdKey,
+        device: &str,
+        max_hold: Option<Duration>,
+    ) -> bool {
+        if !self.key_down(key) {
+            return false;
+        }
+        let held = self.held.get_mut(key).unwrap();
+        held.device = Some(device.to_owned());
+        held.max_hold = max_hold;
         true
     }
 
     /// Release a given key.
     /// Returns `true` if an event was sent.
     pub fn key_up(&mut self, key: &KbdKey) -> bool {
+        self.held.remove(key);
         if let Some(val) = self.key_state.get(key) {
             if !*val {
                 return false;
             }
         }
-        ENIGO.with(|enigo| enigo.borrow_mut().key_up(KbdKey::to_enigo_key(key)));
+        self.backend.key_up(key);
         self.key_state.insert(key.clone(), false);
         true
     }
 
-    /// Returns the number of keys that were reset
+    /// Type a string without changing which keys are held.
+    pub fn text(&mut self, text: &str) {
+        self.backend.text(text);
+    }
+
+    /// Press a mouse button on behalf of `device`.
+    /// Returns `true` if an event was sent.
+    pub fn mouse_down_from(&mut self, button: MouseButton, device: &str) -> bool {
+        if self.buttons.contains_key(&button) {
+            return false;
+        }
+        self.buttons.insert(button, device.to_owned());
+        self.backend.mouse_down(button);
+        true
+    }
+
+    /// Release a mouse button.
+    /// Returns `true` if an event was sent.
+    pub fn mouse_up(&mut self, button: MouseButton) -> bool {
+        if self.buttons.remove(&button).is_none() {
+            return false;
+        }
+        self.backend.mouse_up(button);
+        true
+    }
+
+    /// Move the mouse pointer by `x` pixels right and `y` pixels down.
+    pub fn mouse_move(&mut self, x: i32, y: i32) {
+        self.backend.mouse_move(x, y);
+    }
+
+    /// Move the mouse pointer to a position on the screen.
+    pub fn mouse_move_to(&mut self, x: i32, y: i32) {
+        self.backend.mouse_move_to(x, y);
+    }
+
+    /// Scroll by `x` steps right and `y` steps down.
+    pub fn scroll(&mut self, x: i32, y: i32) {
+        self.backend.scroll(x, y);
+    }
+
+    /// Release every key and mouse button.
+    /// Returns the number of keys and buttons that were reset
     pub fn key_reset(&mut self) -> u32 {
         let mut changes = 0;
         for (key, pressed) in &self.key_state {
             if *pressed {
-                ENIGO.with(|enigo| enigo.borrow_mut().key_up(KbdKey::to_enigo_key(key)));
+                self.backend.key_up(key);
                 changes += 1;
             }
         }
+        for button in self.buttons.keys() {
+            self.backend.mouse_up(*button);
+            changes += 1;
+        }
 
         self.key_state.clear();
+        self.held.clear();
+        self.buttons.clear();
+        changes
+    }
+
+    /// Release every key and mouse button that `device` pressed.
+    /// Returns the number of keys and buttons that were released.
+    pub fn release_device(&mut self, device: &str) -> u32 {
+        let keys: Vec<KbdKey> = self
+            .held
+            .iter()
+            .filter(|&(_, held)| held.device.as_deref() == Some(device))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut changes = 0;
+        for key in &keys {
+            if self.key_up(key) {
+                changes += 1;
+            }
+        }
+
+        let buttons: Vec<MouseButton> = self
+            .buttons
+            .iter()
+            .filter(|&(_, held_by)| held_by == device)
+            .map(|(button, _)| *button)
+            .collect();
+        for button in buttons {
+            if self.mouse_up(button) {
+                changes += 1;
+            }
+        }
         changes
     }
+
+    /// How long a key has been held down, if it's down.
+    #[cfg(test)]
+    pub fn held_for(&self, key: &KbdKey) -> Option<Duration> {
+        self.held.get(key).map(|held| held.since.elapsed())
+    }
+
+    /// Release every key that has been held for longer than its mapping
+    /// allows.  Returns each key that was released, along with how long it
+    /// had been held.
+    pub fn release_stuck(&mut self) -> Vec<(KbdKey, Duration)> {
+        let stuck: Vec<(KbdKey, Duration)> = self
+            .held
+            .iter()
+            .filter_map(|(key, held)| {
+                let held_for = held.since.elapsed();
+                match held.max_hold {
+                    Some(max_hold) if held_for > max_hold => Some((key.clone(), held_for)),
+                    _ => None,
+                }
+            })
+            .collect();
+        for (key, _) in &stuck {
+            self.key_up(key);
+        }
+        stuck
+    }
+}
+
+/// Lets a thread sleep until a deadline, and wakes it early when an earlier
+/// deadline may have come up.
+#[derive(Clone, Default)]
+pub struct Wakeup {
+    /// Set when the thread has been woken, until it next waits
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Wakeup {
+    /// Wake the waiting thread, or stop its next wait from sleeping.
+    pub fn notify(&self) {
+        let (ref woken, ref condvar) = *self.inner;
+        *woken.lock().unwrap() = true;
+        condvar.notify_one();
+    }
+
+    /// Wait until `deadline`, or with no deadline until woken.
+    pub fn wait_until(&self, deadline: Option<Instant>) {
+        let (ref woken, ref condvar) = *self.inner;
+        let mut woken = woken.lock().unwrap();
+        while !*woken {
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    woken = condvar.wait_timeout(woken, deadline - now).unwrap().0;
+                }
+                None => woken = condvar.wait(woken).unwrap(),
+            }
+        }
+        *woken = false;
+    }
 }
 
+/// A note by the device that played it, its channel and the note itself.
+pub type DeviceNote = (String, u8, MidiNote);
+
 /// The object that gets passed to the MIDI callback, containing all our state
 #[derive(Clone, Default)]
 pub struct AppState {
     keygen: Arc<Mutex<KeyGen>>,
     mappings: Arc<Mutex<NoteMappings>>,
+
+    /// Whether each (channel, controller) pair is currently "on"
+    controls: Arc<Mutex<HashMap<(u8, u8), bool>>>,
+
+    /// The latest amount (-1.0 to 1.0) of each continuous input, by channel
+    amounts: Arc<Mutex<HashMap<(u8, ContinuousSource), f32>>>,
+
+    /// The velocity each held note was struck with, by device and channel
+    velocities: Arc<Mutex<HashMap<DeviceNote, u8>>>,
+
+    /// Notes held back while waiting to see if they're part of a chord
+    chords: Arc<Mutex<ChordState>>,
+
+    /// Notes waiting to see whether they're tapped or held
+    gestures: Arc<Mutex<GestureState>>,
+
+    /// Wakes the thread that plays held back notes, when a note is held
+    /// back or released
+    expiry: Wakeup,
+
+    /// How far to shift incoming notes
+    transposer: Arc<Mutex<Transposer>>,
+
+    /// Whether note modifiers are held steady
+    latch: Arc<Mutex<NoteModLatch>>,
+
+    /// Runs mapping sequences in the background
+    scheduler: Scheduler,
 }
 
 impl AppState {
@@ -71,6 +303,14 @@ impl AppState {
         AppState::default()
     }
 
+    /// Create a new state that sends keys through `backend`.
+    pub fn with_backend(backend: Box<dyn KeyBackend + Send>) -> AppState {
+        AppState {
+            keygen: Arc::new(Mutex::new(KeyGen::new(backend))),
+            ..Default::default()
+        }
+    }
+
     pub fn keygen(&self) -> &Arc<Mutex<KeyGen>> {
         &self.keygen
     }
@@ -78,4 +318,169 @@ impl AppState {
     pub fn mappings(&self) -> &Arc<Mutex<NoteMappings>> {
         &self.mappings
     }
+
+    pub fn controls(&self) -> &Arc<Mutex<HashMap<(u8, u8), bool>>> {
+        &self.controls
+    }
+
+    pub fn amounts(&self) -> &Arc<Mutex<HashMap<(u8, ContinuousSource), f32>>> {
+        &self.amounts
+    }
+
+    pub fn velocities(&self) -> &Arc<Mutex<HashMap<DeviceNote, u8>>> {
+        &self.velocities
+    }
+
+    pub fn chords(&self) -> &Arc<Mutex<ChordState>> {
+        &self.chords
+    }
+
+    pub fn gestures(&self) -> &Arc<Mutex<GestureState>> {
+        &self.gestures
+    }
+
+    pub fn expiry(&self) -> &Wakeup {
+        &self.expiry
+    }
+
+    pub fn transposer(&self) -> &Arc<Mutex<Transposer>> {
+        &self.transposer
+    }
+
+    pub fn latch(&self) -> &Arc<Mutex<NoteModLatch>> {
+        &self.latch
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// Release every held key, and forget the notes, chords, taps, latch
+    /// and sequences in progress, so nothing started under one set of
+    /// mappings finishes under another.  Sequences from `keep`, such as
+    /// the one switching layers, carry on.  `keygen` is this state's
+    /// keygen, already locked by the caller.  Returns the number of keys
+    /// released.
+    pub fn reset_notes(&self, keygen: &mut KeyGen, keep: Option<&Source>) -> u32 {
+        let released = keygen.key_reset();
+        self.velocities.lock().unwrap().clear();
+        self.chords.lock().unwrap().clear();
+        self.gestures.lock().unwrap().clear();
+        self.latch.lock().unwrap().clear();
+        match keep {
+            Some(source) => self.scheduler.clear_others(source),
+            None => self.scheduler.clear(),
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::backend::{KeyAction, RecordingBackend};
+
+    fn keygen() -> (KeyGen<RecordingBackend>, RecordingBackend) {
+        let backend = RecordingBackend::new();
+        (KeyGen::new(backend.clone()), backend)
+    }
+
+    #[test]
+    fn repeated_key_down_is_sent_once() {
+        let (mut keygen, backend) = keygen();
+        let a = KbdKey::Layout('a');
+        assert!(keygen.key_down(&a));
+        assert!(!keygen.key_down(&a));
+        assert!(!keygen.key_down_from(&a, "kbd", None));
+        assert!(keygen.key_up(&a));
+        assert!(!keygen.key_up(&a));
+        assert_eq!(
+            backend.actions(),
+            vec![KeyAction::Down(a.clone()), KeyAction::Up(a)]
+        );
+    }
+
+    #[test]
+    fn release_device() {
+        let (mut keygen, backend) = keygen();
+        let a = KbdKey::Layout('a');
+        let b = KbdKey::Layout('b');
+        keygen.key_down_from(&a, "one", None);
+        keygen.key_down_from(&b, "two", None);
+        keygen.key_down(&KbdKey::Shift);
+        keygen.mouse_down_from(MouseButton::Left, "one");
+        keygen.mouse_down_from(MouseButton::Right, "two");
+
+        assert_eq!(keygen.release_device("one"), 2);
+        assert_eq!(keygen.release_device("one"), 0);
+        assert!(keygen.held_for(&a).is_none());
+        assert!(keygen.held_for(&b).is_some());
+        assert!(keygen.held_for(&KbdKey::Shift).is_some());
+
+        let actions = backend.actions();
+        assert_eq!(actions.len(), 7);
+        assert!(actions[5..].contains(&KeyAction::Up(a)));
+        assert!(actions[5..].contains(&KeyAction::MouseUp(MouseButton::Left)));
+    }
+
+    #[test]
+    fn release_stuck() {
+        let (mut keygen, backend) = keygen();
+        let a = KbdKey::Layout('a');
+        let b = KbdKey::Layout('b');
+        keygen.key_down_from(&a, "kbd", Some(Duration::from_millis(1)));
+        keygen.key_down_from(&b, "kbd", Some(Duration::from_secs(60)));
+        keygen.key_down(&KbdKey::Shift);
+        thread::sleep(Duration::from_millis(5));
+
+        let stuck = keygen.release_stuck();
+        assert_eq!(stuck.len(), 1);
+        assert_eq!(stuck[0].0, a);
+        assert!(stuck[0].1 >= Duration::from_millis(1));
+        assert!(keygen.release_stuck().is_empty());
+        assert_eq!(backend.actions().last(), Some(&KeyAction::Up(a)));
+    }
+
+    #[test]
+    fn wakeup() {
+        let wakeup = Wakeup::default();
+        // A notification before waiting isn't lost
+        wakeup.notify();
+        wakeup.wait_until(None);
+
+        let start = Instant::now();
+        wakeup.wait_until(Some(start + Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let notifier = wakeup.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            notifier.notify();
+        });
+        let start = Instant::now();
+        wakeup.wait_until(None);
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn key_reset_releases_keys_and_buttons() {
+        let (mut keygen, backend) = keygen();
+        let a = KbdKey::Layout('a');
+        keygen.key_down(&a);
+        keygen.key_down(&KbdKey::Shift);
+        keygen.key_up(&KbdKey::Shift);
+        keygen.mouse_down_from(MouseButton::Middle, "kbd");
+
+        assert_eq!(keygen.key_reset(), 2);
+        assert_eq!(keygen.key_reset(), 0);
+        assert!(!keygen.mouse_up(MouseButton::Middle));
+        let actions = backend.actions();
+        assert!(actions[4..].contains(&KeyAction::Up(a)));
+        assert!(actions[4..].contains(&KeyAction::MouseUp(MouseButton::Middle)));
+
+        // Nothing is left held, so pressing again sends again
+        assert!(keygen.mouse_down_from(MouseButton::Middle, "kbd"));
+    }
 }
@@ -0,0 +1,231 @@
+use std::cell::RefCell;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+use enigo::{Enigo, KeyboardControllable, MouseControllable};
+
+use crate::notemappings::{KbdKey, MouseButton};
+
+thread_local!(static ENIGO: RefCell<Enigo> = RefCell::new(Default::default()));
+
+/// Something that can press and release keys, and work the mouse, on
+/// behalf of `KeyGen`.
+pub trait KeyBackend {
+    /// Press a key.
+    fn key_down(&mut self, key: &KbdKey);
+
+    /// Release a key.
+    fn key_up(&mut self, key: &KbdKey);
+
+    /// Type a string, which may contain any Unicode characters.
+    fn text(&mut self, text: &str);
+
+    /// Press a mouse button.
+    fn mouse_down(&mut self, button: MouseButton);
+
+    /// Release a mouse button.
+    fn mouse_up(&mut self, button: MouseButton);
+
+    /// Move the mouse pointer by `x` pixels right and `y` pixels down.
+    fn mouse_move(&mut self, x: i32, y: i32);
+
+    /// Move the mouse pointer to `x`, `y` pixels from the top left of the
+    /// screen.
+    fn mouse_move_to(&mut self, x: i32, y: i32);
+
+    /// Scroll by `x` steps right and `y` steps down.
+    fn scroll(&mut self, x: i32, y: i32);
+}
+
+impl<B: KeyBackend + ?Sized> KeyBackend for Box<B> {
+    fn key_down(&mut self, key: &KbdKey) {
+        (**self).key_down(key)
+    }
+
+    fn key_up(&mut self, key: &KbdKey) {
+        (**self).key_up(key)
+    }
+
+    fn text(&mut self, text: &str) {
+        (**self).text(text)
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) {
+        (**self).mouse_down(button)
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) {
+        (**self).mouse_up(button)
+    }
+
+    fn mouse_move(&mut self, x: i32, y: i32) {
+        (**self).mouse_move(x, y)
+    }
+
+    fn mouse_move_to(&mut self, x: i32, y: i32) {
+        (**self).mouse_move_to(x, y)
+    }
+
+    fn scroll(&mut self, x: i32, y: i32) {
+        (**self).scroll(x, y)
+    }
+}
+
+/// Sends keys to the OS using enigo.
+/// Enigo isn't `Send`, so each thread gets its own instance.
+#[derive(Default)]
+pub struct EnigoBackend;
+
+impl KeyBackend for EnigoBackend {
+    fn key_down(&mut self, key: &KbdKey) {
+        ENIGO.with(|enigo| enigo.borrow_mut().key_down(KbdKey::to_enigo_key(key)));
+    }
+
+    fn key_up(&mut self, key: &KbdKey) {
+        ENIGO.with(|enigo| enigo.borrow_mut().key_up(KbdKey::to_enigo_key(key)));
+    }
+
+    fn text(&mut self, text: &str) {
+        ENIGO.with(|enigo| enigo.borrow_mut().key_sequence(text));
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) {
+        ENIGO.with(|enigo| enigo.borrow_mut().mouse_down(button.to_enigo_button()));
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) {
+        ENIGO.with(|enigo| enigo.borrow_mut().mouse_up(button.to_enigo_button()));
+    }
+
+    fn mouse_move(&mut self, x: i32, y: i32) {
+        ENIGO.with(|enigo| enigo.borrow_mut().mouse_move_relative(x, y));
+    }
+
+    fn mouse_move_to(&mut self, x: i32, y: i32) {
+        ENIGO.with(|enigo| enigo.borrow_mut().mouse_move_to(x, y));
+    }
+
+    fn scroll(&mut self, x: i32, y: i32) {
+        ENIGO.with(|enigo| {
+            let mut enigo = enigo.borrow_mut();
+            if x != 0 {
+                enigo.mouse_scroll_x(x);
+            }
+            if y != 0 {
+                enigo.mouse_scroll_y(y);
+            }
+        });
+    }
+}
+
+/// Prints keys to stdout instead of pressing them, for trying out mappings.
+#[derive(Default)]
+pub struct PrintBackend;
+
+impl KeyBackend for PrintBackend {
+    fn key_down(&mut self, key: &KbdKey) {
+        println!("Key down: {}", key);
+    }
+
+    fn key_up(&mut self, key: &KbdKey) {
+        println!("Key up: {}", key);
+    }
+
+    fn text(&mut self, text: &str) {
+        println!("Text: {:?}", text);
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) {
+        println!("Mouse down: {}", button);
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) {
+        println!("Mouse up: {}", button);
+    }
+
+    fn mouse_move(&mut self, x: i32, y: i32) {
+        println!("Mouse move: {}, {}", x, y);
+    }
+
+    fn mouse_move_to(&mut self, x: i32, y: i32) {
+        println!("Mouse move to: {}, {}", x, y);
+    }
+
+    fn scroll(&mut self, x: i32, y: i32) {
+        println!("Scroll: {}, {}", x, y);
+    }
+}
+
+/// A single action taken by a backend.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyAction {
+    Down(KbdKey),
+    Up(KbdKey),
+    Text(String),
+    MouseDown(MouseButton),
+    MouseUp(MouseButton),
+    MouseMove(i32, i32),
+    MouseMoveTo(i32, i32),
+    Scroll(i32, i32),
+}
+
+/// Records keys in memory, so tests can check what would have been pressed.
+/// Clones share the same record, so a test can keep one while another is
+/// handed to `KeyGen`.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct RecordingBackend {
+    actions: Arc<Mutex<Vec<KeyAction>>>,
+}
+
+#[cfg(test)]
+impl RecordingBackend {
+    pub fn new() -> RecordingBackend {
+        RecordingBackend::default()
+    }
+
+    /// Everything that has been pressed and released, in order.
+    pub fn actions(&self) -> Vec<KeyAction> {
+        self.actions.lock().unwrap().clone()
+    }
+
+    fn record(&mut self, action: KeyAction) {
+        self.actions.lock().unwrap().push(action);
+    }
+}
+
+#[cfg(test)]
+impl KeyBackend for RecordingBackend {
+    fn key_down(&mut self, key: &KbdKey) {
+        self.record(KeyAction::Down(key.clone()));
+    }
+
+    fn key_up(&mut self, key: &KbdKey) {
+        self.record(KeyAction::Up(key.clone()));
+    }
+
+    fn text(&mut self, text: &str) {
+        self.record(KeyAction::Text(text.to_owned()));
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) {
+        self.record(KeyAction::MouseDown(button));
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) {
+        self.record(KeyAction::MouseUp(button));
+    }
+
+    fn mouse_move(&mut self, x: i32, y: i32) {
+        self.record(KeyAction::MouseMove(x, y));
+    }
+
+    fn mouse_move_to(&mut self, x: i32, y: i32) {
+        self.record(KeyAction::MouseMoveTo(x, y));
+    }
+
+    fn scroll(&mut self, x: i32, y: i32) {
+        self.record(KeyAction::Scroll(x, y));
+    }
+}
@@ -0,0 +1,441 @@
+use std::time::{Duration, Instant};
+
+use crate::midi::MidiNote;
+use crate::notemappings::ChordMapping;
+
+/// What to do once chord detection has had its say about a note.
+#[derive(Debug)]
+pub enum ChordOutput {
+    /// Handle a note the usual way, through its own mapping.
+    Note {
+        device: String,
+        channel: u8,
+        note: MidiNote,
+        on: bool,
+        velocity: u8,
+    },
+
+    /// Run a chord's on or off sequence.
+    Chord {
+        device: String,
+        mapping: ChordMapping,
+        on: bool,
+    },
+}
+
+/// A note that is being held back in case it's part of a chord.
+struct Pending {
+    device: String,
+    channel: u8,
+    note: MidiNote,
+    velocity: u8,
+    at: Instant,
+    /// How long to wait for the rest of a chord, which is the longest
+    /// window of any chord the note is in
+    window: Duration,
+    /// Whether the note was released while it was held back
+    released: bool,
+}
+
+impl Pending {
+    fn is(&self, device: &str, channel: u8, note: MidiNote) -> bool {
+        self.device == device && self.channel == channel && self.note == note
+    }
+}
+
+/// A chord that has been played, and whose notes aren't all released yet.
+struct Active {
+    device: String,
+    mapping: ChordMapping,
+    /// Notes of the chord that are still down
+    down: Vec<MidiNote>,
+    /// Whether the off sequence has run
+    off_sent: bool,
+}
+
+/// Turns notes into chords.
+///
+/// A note that belongs to any chord is held back until one of these
+/// happens:
+///
+/// * Every note of a chord has been pressed within the chord's window.  The
+///   chord's on sequence runs instead of the notes' own mappings, and its
+///   off sequence runs as soon as any of its notes is released.  Releasing
+///   the rest of the notes does nothing.
+/// * The window runs out, or a note that isn't in any chord is pressed.
+///   This is a partial chord, so the held back notes are played one by one
+///   through their own mappings, in the order they were pressed, and any
+///   that were already released are released again straight away.
+///
+/// When one chord's notes are a subset of another's, the first one to be
+/// completed wins.  Each device is watched separately, so notes from two
+/// keyboards never make up a chord together, and pressing a held back note
+/// again gives up on the chord it was part of, as for a partial chord.
+#[derive(Default)]
+pub struct ChordState {
+    pending: Vec<Pending>,
+    active: Vec<Active>,
+}
+
+impl ChordState {
+    pub fn new() -> ChordState {
+        ChordState::default()
+    }
+
+    /// Handle a note being pressed.  `chords` are the chord mappings for
+    /// the note's channel.
+    pub fn note_on(
+        &mut self,
+        device: &str,
+        channel: u8,
+        note: MidiNote,
+        velocity: u8,
+        now: Instant,
+        chords: &[ChordMapping],
+    ) -> Vec<ChordOutput> {
+        let mut outputs = self.expire(now);
+
+        let window = chords
+            .iter()
+            .filter(|chord| chord.notes().contains(&note))
+            .map(|chord| Duration::from_millis(chord.window()))
+            .max();
+        let window = match window {
+            Some(window) => window,
+            None => {
+                outputs.extend(self.flush(device, channel));
+                outputs.push(ChordOutput::Note {
+                    device: device.to_owned(),
+                    channel,
+                    note,
+                    on: true,
+                    velocity,
+                });
+                return outputs;
+            }
+        };
+
+        // Pressing a note that's already held back starts a new attempt, so
+        // the notes of the old one are played as they were.
+        if self.pending.iter().any(|p| p.is(device, channel, note)) {
+            outputs.extend(self.flush(device, channel));
+        }
+
+        self.pending.push(Pending {
+            device: device.to_owned(),
+            channel,
+            note,
+            velocity,
+            at: now,
+            window,
+            released: false,
+        });
+
+        // Prefer the chord with the most notes if several are completed by
+        // this note.
+        let complete = chords
+            .iter()
+            .filter(|chord| chord.notes().contains(&note))
+            .filter(|chord| {
+                chord.notes().iter().all(|chord_note| {
+                    self.pending
+                        .iter()
+                        .any(|p| p.is(device, channel, *chord_note))
+                })
+            })
+            .max_by_key(|chord| chord.notes().len());
+        if let Some(chord) = complete {
+            let (used, rest): (Vec<Pending>, Vec<Pending>) =
+                self.pending.drain(..).partition(|p| {
+                    p.device == device && p.channel == channel && chord.notes().contains(&p.note)
+                });
+            self.pending = rest;
+
+            // Anything else held back from this device and channel came
+            // first, so play it before the chord.
+            outputs.extend(self.flush(device, channel));
+            outputs.push(ChordOutput::Chord {
+                device: device.to_owned(),
+                mapping: chord.clone(),
+                on: true,
+            });
+            let mut active = Active {
+                device: device.to_owned(),
+                mapping: chord.clone(),
+                down: used
+                    .iter()
+                    .filter(|p| !p.released)
+                    .map(|p| p.note)
+                    .collect(),
+                off_sent: false,
+            };
+            if active.down.len() < used.len() {
+                outputs.push(active.release());
+            }
+            if !active.down.is_empty() {
+                self.active.push(active);
+            }
+        }
+        outputs
+    }
+
+    /// Handle a note being released.
+    pub fn note_off(
+        &mut self,
+        device: &str,
+        channel: u8,
+        note: MidiNote,
+        now: Instant,
+    ) -> Vec<ChordOutput> {
+        let mut outputs = self.expire(now);
+
+        if let Some(idx) = self.active.iter().position(|a| {
+            a.device == device && a.mapping.channel() == channel && a.down.contains(&note)
+        }) {
+            let active = &mut self.active[idx];
+            active.down.retain(|n| *n != note);
+            if !active.off_sent {
+                outputs.push(active.release());
+            }
+            if active.down.is_empty() {
+                self.active.remove(idx);
+            }
+            return outputs;
+        }
+
+        if let Some(pending) = self
+            .pending
+            .iter_mut()
+            .find(|p| p.is(device, channel, note) && !p.released)
+        {
+            pending.released = true;
+            return outputs;
+        }
+
+        outputs.push(ChordOutput::Note {
+            device: device.to_owned(),
+            channel,
+            note,
+            on: false,
+            velocity: 0,
+        });
+        outputs
+    }
+
+    /// Give up on chords whose window has run out, and play their notes
+    /// individually.
+    pub fn expire(&mut self, now: Instant) -> Vec<ChordOutput> {
+        let mut outputs = vec![];
+        while let Some((device, channel)) = self
+            .pending
+            .iter()
+            .find(|p| now.duration_since(p.at) >= p.window)
+            .map(|p| (p.device.clone(), p.channel))
+        {
+            outputs.extend(self.flush(&device, channel));
+        }
+        outputs
+    }
+
+    /// When the next held back note's window runs out, if any are held back.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.pending.iter().map(|p| p.at + p.window).min()
+    }
+
+    /// Forget every held back note and chord.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.active.clear();
+    }
+
+    /// Forget the notes and chords played on `device`.
+    pub fn clear_device(&mut self, device: &str) {
+        self.pending.retain(|p| p.device != device);
+        self.active.retain(|a| a.device != device);
+    }
+
+    /// Play every note held back from `device` on `channel` individually.
+    fn flush(&mut self, device: &str, channel: u8) -> Vec<ChordOutput> {
+        let (flushed, rest): (Vec<Pending>, Vec<Pending>) = self
+            .pending
+            .drain(..)
+            .partition(|p| p.device == device && p.channel == channel);
+        self.pending = rest;
+
+        let mut outputs = vec![];
+        for p in flushed {
+            outputs.push(ChordOutput::Note {
+                device: p.device.clone(),
+                channel,
+                note: p.note,
+                on: true,
+                velocity: p.velocity,
+            });
+            if p.released {
+                outputs.push(ChordOutput::Note {
+                    device: p.device,
+                    channel,
+                    note: p.note,
+                    on: false,
+                    velocity: 0,
+                });
+            }
+        }
+        outputs
+    }
+}
+
+impl Active {
+    fn release(&mut self) -> ChordOutput {
+        self.off_sent = true;
+        ChordOutput::Chord {
+            device: self.device.clone(),
+            mapping: self.mapping.clone(),
+            on: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// C4+E4+G4 on channel 0, with a 50ms window.
+    fn c_major() -> Vec<ChordMapping> {
+        vec![ChordMapping::new(
+            &[MidiNote::C4, MidiNote::E4, MidiNote::G4],
+            0,
+            50,
+        )]
+    }
+
+    /// Summarize outputs as e.g. "kbd C4 on" or "kbd chord off".
+    fn describe(outputs: Vec<ChordOutput>) -> Vec<String> {
+        outputs
+            .into_iter()
+            .map(|output| match output {
+                ChordOutput::Note {
+                    device, note, on, ..
+                } => format!("{} {:?} {}", device, note, if on { "on" } else { "off" }),
+                ChordOutput::Chord { device, on, .. } => {
+                    format!("{} chord {}", device, if on { "on" } else { "off" })
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chord_completes() {
+        let chords = c_major();
+        let mut state = ChordState::new();
+        let start = Instant::now();
+        let mut on =
+            |note, at| describe(state.note_on("kbd", 0, note, 100, start + ms(at), &chords));
+        assert!(on(MidiNote::C4, 0).is_empty());
+        assert!(on(MidiNote::G4, 10).is_empty());
+        assert_eq!(on(MidiNote::E4, 20), vec!["kbd chord on"]);
+
+        assert_eq!(
+            describe(state.note_off("kbd", 0, MidiNote::E4, start + ms(100))),
+            vec!["kbd chord off"]
+        );
+        assert!(describe(state.note_off("kbd", 0, MidiNote::C4, start + ms(110))).is_empty());
+        assert!(describe(state.note_off("kbd", 0, MidiNote::G4, start + ms(120))).is_empty());
+        assert_eq!(state.next_expiry(), None);
+    }
+
+    #[test]
+    fn times_out_into_notes() {
+        let chords = c_major();
+        let mut state = ChordState::new();
+        let start = Instant::now();
+        state.note_on("kbd", 0, MidiNote::C4, 100, start, &chords);
+        state.note_on("kbd", 0, MidiNote::E4, 100, start + ms(10), &chords);
+        assert_eq!(state.next_expiry(), Some(start + ms(50)));
+
+        assert!(describe(state.expire(start + ms(49))).is_empty());
+        assert_eq!(
+            describe(state.expire(start + ms(50))),
+            vec!["kbd C4 on", "kbd E4 on"]
+        );
+        assert_eq!(state.next_expiry(), None);
+        assert_eq!(
+            describe(state.note_off("kbd", 0, MidiNote::C4, start + ms(60))),
+            vec!["kbd C4 off"]
+        );
+    }
+
+    #[test]
+    fn other_note_flushes_partial_chord() {
+        let chords = c_major();
+        let mut state = ChordState::new();
+        let start = Instant::now();
+        state.note_on("kbd", 0, MidiNote::C4, 100, start, &chords);
+        assert_eq!(
+            describe(state.note_on("kbd", 0, MidiNote::D4, 100, start + ms(5), &chords)),
+            vec!["kbd C4 on", "kbd D4 on"]
+        );
+    }
+
+    #[test]
+    fn partial_release() {
+        let chords = c_major();
+        let mut state = ChordState::new();
+        let start = Instant::now();
+        state.note_on("kbd", 0, MidiNote::C4, 100, start, &chords);
+        state.note_on("kbd", 0, MidiNote::E4, 100, start + ms(5), &chords);
+        // Released while still held back, so nothing happens yet
+        assert!(describe(state.note_off("kbd", 0, MidiNote::C4, start + ms(10))).is_empty());
+        assert_eq!(
+            describe(state.expire(start + ms(60))),
+            vec!["kbd C4 on", "kbd C4 off", "kbd E4 on"]
+        );
+    }
+
+    #[test]
+    fn repress_starts_a_new_attempt() {
+        let chords = c_major();
+        let mut state = ChordState::new();
+        let start = Instant::now();
+        state.note_on("kbd", 0, MidiNote::C4, 100, start, &chords);
+        state.note_off("kbd", 0, MidiNote::C4, start + ms(5));
+        assert_eq!(
+            describe(state.note_on("kbd", 0, MidiNote::C4, 100, start + ms(10), &chords)),
+            vec!["kbd C4 on", "kbd C4 off"]
+        );
+        state.note_on("kbd", 0, MidiNote::E4, 100, start + ms(15), &chords);
+        assert_eq!(
+            describe(state.note_on("kbd", 0, MidiNote::G4, 100, start + ms(20), &chords)),
+            vec!["kbd chord on"]
+        );
+        // The chord stays on until one of its notes is released.
+        assert_eq!(
+            describe(state.note_off("kbd", 0, MidiNote::C4, start + ms(200))),
+            vec!["kbd chord off"]
+        );
+    }
+
+    #[test]
+    fn devices_are_kept_apart() {
+        let chords = c_major();
+        let mut state = ChordState::new();
+        let start = Instant::now();
+        state.note_on("one", 0, MidiNote::C4, 100, start, &chords);
+        state.note_on("two", 0, MidiNote::E4, 100, start + ms(5), &chords);
+        assert!(
+            describe(state.note_on("one", 0, MidiNote::G4, 100, start + ms(10), &chords))
+                .is_empty()
+        );
+        assert_eq!(
+            describe(state.note_on("two", 0, MidiNote::D4, 100, start + ms(15), &chords)),
+            vec!["two E4 on", "two D4 on"]
+        );
+
+        state.clear_device("one");
+        assert!(describe(state.expire(start + ms(100))).is_empty());
+    }
+}
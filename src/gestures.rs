@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::midi::MidiNote;
+use crate::notemappings::{Event, NoteMapping};
+
+/// A sequence to run once a gesture has been worked out.
+#[derive(Debug)]
+pub struct GestureOutput {
+    pub device: String,
+    pub channel: u8,
+    pub note: MidiNote,
+    pub events: Vec<Event>,
+    /// How long, in milliseconds, keys pressed by the sequence may be held
+    pub max_hold: Option<u64>,
+}
+
+/// A note whose mapping is waiting to see how it's played.
+struct Pending {
+    device: String,
+    mapping: NoteMapping,
+    /// The velocity of the first tap
+    velocity: u8,
+    /// The number of times the note has been pressed so far
+    count: u8,
+    last_press: Instant,
+    /// Whether the note is held down
+    down: bool,
+    /// Once the gesture has been worked out while the note is still down,
+    /// the sequence to run when it's released
+    release: Option<Vec<Event>>,
+}
+
+impl Pending {
+    fn output(&self, channel: u8, note: MidiNote, events: Vec<Event>) -> GestureOutput {
+        GestureOutput {
+            device: self.device.clone(),
+            channel,
+            note,
+            events,
+            max_hold: self.mapping.max_hold,
+        }
+    }
+
+    /// Returns `true` if a long press is still possible, in which case it
+    /// decides what happens rather than the tap window.
+    fn may_be_long(&self) -> bool {
+        self.down && self.count == 1 && self.mapping.long_press.is_some()
+    }
+
+    /// When the note becomes a long press, or its window for another tap
+    /// runs out.
+    fn deadline(&self) -> Instant {
+        let wait = match self.mapping.long_press {
+            Some(ref long_press) if self.may_be_long() => long_press.threshold(),
+            _ => self.mapping.tap_window().unwrap_or(0),
+        };
+        self.last_press + Duration::from_millis(wait)
+    }
+}
+
+/// Tells taps, multiple taps and long presses apart.
+///
+/// A note with any of these does nothing when it's pressed.  Instead:
+///
+/// * If it's held past its long press threshold, the long press on
+///   sequence runs, and the long press off sequence runs when it's
+///   released.
+/// * Otherwise each press within the tap window of the one before adds to
+///   the count of taps.  Once no more taps can follow, because the window
+///   has run out or the count is as high as the mapping goes, the
+///   sequences for that many taps run.  If the note is still down, its off
+///   sequence waits until it's released.
+/// * A note with a long press but no multiple taps doesn't wait for the
+///   window, so a tap runs as soon as it's released.
+#[derive(Default)]
+pub struct GestureState {
+    /// Notes being watched, by device, channel and note
+    pending: HashMap<(String, u8, MidiNote), Pending>,
+}
+
+impl GestureState {
+    pub fn new() -> GestureState {
+        GestureState::default()
+    }
+
+    /// Handle a note being pressed.  `mapping` is the note's mapping,
+    /// which should have a long press or multiple taps.
+    pub fn note_on(
+        &mut self,
+        device: &str,
+        channel: u8,
+        note: MidiNote,
+        velocity: u8,
+        mapping: NoteMapping,
+        now: Instant,
+    ) -> Vec<GestureOutput> {
+        let mut outputs = self.expire(now);
+
+        let key = (device.to_owned(), channel, note);
+        let pending = match self.pending.get_mut(&key) {
+            // Still waiting for another tap, since `expire()` would have
+            // removed it otherwise.
+            Some(pending) if !pending.down && pending.release.is_none() => {
+                pending.count += 1;
+                pending.last_press = now;
+                pending.down = true;
+                pending
+            }
+            _ => {
+                self.pending.insert(
+                    key.clone(),
+                    Pending {
+                        device: device.to_owned(),
+                        mapping,
+                        velocity,
+                        count: 1,
+                        last_press: now,
+                        down: true,
+                        release: None,
+                    },
+                );
+                self.pending.get_mut(&key).unwrap()
+            }
+        };
+
+        if pending.count == pending.mapping.max_taps() && !pending.may_be_long() {
+            let (on, off) = pending
+                .mapping
+                .tap_sequences(pending.count, pending.velocity);
+            pending.release = Some(off);
+            outputs.push(pending.output(channel, note, on));
+        }
+        outputs
+    }
+
+    /// Handle a note being released.  Returns `None` if the note isn't
+    /// being watched, so it should be handled the usual way.
+    pub fn note_off(
+        &mut self,
+        device: &str,
+        channel: u8,
+        note: MidiNote,
+        now: Instant,
+    ) -> Option<Vec<GestureOutput>> {
+        let mut outputs = self.expire(now);
+
+        let key = (device.to_owned(), channel, note);
+        let pending = self.pending.get_mut(&key)?;
+        if !pending.down {
+            return None;
+        }
+        pending.down = false;
+        if let Some(release) = pending.release.take() {
+            outputs.push(pending.output(channel, note, release));
+            self.pending.remove(&key);
+        } else if pending.mapping.taps.is_empty() {
+            let (mut on, off) = pending.mapping.tap_sequences(1, pending.velocity);
+            on.extend(off);
+            outputs.push(pending.output(channel, note, on));
+            self.pending.remove(&key);
+        }
+        Some(outputs)
+    }
+
+    /// Work out the gestures of notes that have now been held for a long
+    /// press, or whose tap window has run out.
+    pub fn expire(&mut self, now: Instant) -> Vec<GestureOutput> {
+        let mut outputs = vec![];
+        let mut finished = vec![];
+        for (key, pending) in &mut self.pending {
+            let (_, channel, note) = *key;
+            if pending.release.is_some() || now < pending.deadline() {
+                continue;
+            }
+
+            if pending.may_be_long() {
+                let long_press = pending.mapping.long_press.as_ref().unwrap();
+                pending.release = Some(long_press.off.clone());
+                let on = long_press.on.clone();
+                outputs.push(pending.output(channel, note, on));
+                continue;
+            }
+
+            let (mut on, off) = pending
+                .mapping
+                .tap_sequences(pending.count, pending.velocity);
+            if pending.down {
+                pending.release = Some(off);
+            } else {
+                on.extend(off);
+                finished.push(key.clone());
+            }
+            outputs.push(pending.output(channel, note, on));
+        }
+        for key in finished {
+            self.pending.remove(&key);
+        }
+        outputs
+    }
+
+    /// When `expire()` next has something to do, if any notes are waiting
+    /// to see how they're played.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .filter(|pending| pending.release.is_none())
+            .map(Pending::deadline)
+            .min()
+    }
+
+    /// Forget every note being watched.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Forget the notes being watched from `device`.
+    pub fn clear_device(&mut self, device: &str) {
+        self.pending.retain(|_, pending| pending.device != device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notemappings::{KbdKey, LongPress, MultiTap};
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn key(c: char) -> (Event, Event) {
+        (
+            Event::KeyDown(KbdKey::Layout(c)),
+            Event::KeyUp(KbdKey::Layout(c)),
+        )
+    }
+
+    /// A mapping for C4 that types `a` when tapped, with a 250ms long press
+    /// that holds Shift.
+    fn long_press_mapping() -> NoteMapping {
+        let mut mapping = NoteMapping::new(MidiNote::C4, 0, None);
+        let (down, up) = key('a');
+        mapping.on = vec![down];
+        mapping.off = vec![up];
+        let mut long_press = LongPress::new(250);
+        long_press.on = vec![Event::KeyDown(KbdKey::Shift)];
+        long_press.off = vec![Event::KeyUp(KbdKey::Shift)];
+        mapping.long_press = Some(long_press);
+        mapping
+    }
+
+    /// The events of each output, in order.
+    fn events(outputs: Vec<GestureOutput>) -> Vec<Vec<Event>> {
+        outputs.into_iter().map(|output| output.events).collect()
+    }
+
+    #[test]
+    fn tap_runs_on_release() {
+        let mut state = GestureState::new();
+        let start = Instant::now();
+        let on = state.note_on("kbd", 0, MidiNote::C4, 100, long_press_mapping(), start);
+        assert!(on.is_empty());
+        assert_eq!(state.next_expiry(), Some(start + ms(250)));
+
+        let off = state
+            .note_off("kbd", 0, MidiNote::C4, start + ms(100))
+            .unwrap();
+        let (down, up) = key('a');
+        assert_eq!(events(off), vec![vec![down, up]]);
+        assert_eq!(state.next_expiry(), None);
+        assert!(state.expire(start + ms(300)).is_empty());
+    }
+
+    #[test]
+    fn hold_becomes_long_press() {
+        let mut state = GestureState::new();
+        let start = Instant::now();
+        state.note_on("kbd", 0, MidiNote::C4, 100, long_press_mapping(), start);
+        assert!(state.expire(start + ms(249)).is_empty());
+        assert_eq!(
+            events(state.expire(start + ms(250))),
+            vec![vec![Event::KeyDown(KbdKey::Shift)]]
+        );
+        assert_eq!(state.next_expiry(), None);
+        assert!(state.expire(start + ms(1000)).is_empty());
+
+        let off = state
+            .note_off("kbd", 0, MidiNote::C4, start + ms(1000))
+            .unwrap();
+        assert_eq!(events(off), vec![vec![Event::KeyUp(KbdKey::Shift)]]);
+        // Nothing is being watched now, so the next release is handled
+        // the usual way.
+        assert!(state
+            .note_off("kbd", 0, MidiNote::C4, start + ms(1100))
+            .is_none());
+    }
+
+    #[test]
+    fn clear_device_forgets_its_notes() {
+        let mut state = GestureState::new();
+        let start = Instant::now();
+        state.note_on("one", 0, MidiNote::C4, 100, long_press_mapping(), start);
+        state.note_on("two", 0, MidiNote::D4, 100, long_press_mapping(), start);
+        state.clear_device("one");
+        assert!(state
+            .note_off("one", 0, MidiNote::C4, start + ms(10))
+            .is_none());
+        assert!(state
+            .note_off("two", 0, MidiNote::D4, start + ms(10))
+            .is_some());
+    }
+
+    #[test]
+    fn devices_are_kept_apart() {
+        let mut state = GestureState::new();
+        let start = Instant::now();
+        state.note_on("one", 0, MidiNote::C4, 100, long_press_mapping(), start);
+        let on = state.note_on(
+            "two",
+            0,
+            MidiNote::C4,
+            100,
+            long_press_mapping(),
+            start + ms(100),
+        );
+        assert!(on.is_empty());
+
+        // The second device's tap doesn't end the first one's press.
+        let off = state
+            .note_off("two", 0, MidiNote::C4, start + ms(150))
+            .unwrap();
+        assert_eq!(off.len(), 1);
+        assert_eq!(off[0].device, "two");
+        let (down, up) = key('a');
+        assert_eq!(events(off), vec![vec![down, up]]);
+
+        let long = state.expire(start + ms(250));
+        assert_eq!(long.len(), 1);
+        assert_eq!(long[0].device, "one");
+        assert_eq!(events(long), vec![vec![Event::KeyDown(KbdKey::Shift)]]);
+        let off = state
+            .note_off("one", 0, MidiNote::C4, start + ms(400))
+            .unwrap();
+        assert_eq!(events(off), vec![vec![Event::KeyUp(KbdKey::Shift)]]);
+    }
+
+    /// A mapping for C4 that types `a` when tapped, `b` when tapped twice and
+    /// `c` when tapped three times, each within 300ms of the one before.
+    fn multi_tap_mapping() -> NoteMapping {
+        let mut mapping = NoteMapping::new(MidiNote::C4, 0, None);
+        let (down, up) = key('a');
+        mapping.on = vec![down];
+        mapping.off = vec![up];
+        for (count, c) in &[(2, 'b'), (3, 'c')] {
+            let mut multi_tap = MultiTap::new(*count, 300);
+            let (down, up) = key(*c);
+            multi_tap.on = vec![down];
+            multi_tap.off = vec![up];
+            mapping.taps.push(multi_tap);
+        }
+        mapping
+    }
+
+    /// Tap C4 at each time, holding it for 50ms.
+    fn tap(state: &mut GestureState, start: Instant, times: &[u64]) -> Vec<Vec<Event>> {
+        let mut outputs = vec![];
+        for &at in times {
+            let mapping = multi_tap_mapping();
+            outputs.extend(state.note_on("kbd", 0, MidiNote::C4, 100, mapping, start + ms(at)));
+            outputs.extend(
+                state
+                    .note_off("kbd", 0, MidiNote::C4, start + ms(at + 50))
+                    .unwrap(),
+            );
+        }
+        events(outputs)
+    }
+
+    #[test]
+    fn single_tap_waits_for_the_window() {
+        let mut state = GestureState::new();
+        let start = Instant::now();
+        assert!(tap(&mut state, start, &[0]).is_empty());
+        assert_eq!(state.next_expiry(), Some(start + ms(300)));
+        assert!(state.expire(start + ms(299)).is_empty());
+
+        let (down, up) = key('a');
+        assert_eq!(events(state.expire(start + ms(300))), vec![vec![down, up]]);
+        assert_eq!(state.next_expiry(), None);
+    }
+
+    #[test]
+    fn double_tap() {
+        let mut state = GestureState::new();
+        let start = Instant::now();
+        assert!(tap(&mut state, start, &[0, 200]).is_empty());
+        // The window runs from the latest press
+        assert_eq!(state.next_expiry(), Some(start + ms(500)));
+        let (down, up) = key('b');
+        assert_eq!(events(state.expire(start + ms(500))), vec![vec![down, up]]);
+    }
+
+    #[test]
+    fn triple_tap_runs_straight_away() {
+        let mut state = GestureState::new();
+        let start = Instant::now();
+        let mapping = multi_tap_mapping();
+        assert!(tap(&mut state, start, &[0, 100]).is_empty());
+
+        // The third press can't be followed by another tap, so it runs
+        // as soon as it's pressed, and its release waits for the note.
+        let on = state.note_on("kbd", 0, MidiNote::C4, 100, mapping, start + ms(200));
+        let (down, up) = key('c');
+        assert_eq!(events(on), vec![vec![down]]);
+        assert_eq!(state.next_expiry(), None);
+        let off = state
+            .note_off("kbd", 0, MidiNote::C4, start + ms(400))
+            .unwrap();
+        assert_eq!(events(off), vec![vec![up]]);
+    }
+
+    #[test]
+    fn late_tap_starts_again() {
+        let mut state = GestureState::new();
+        let start = Instant::now();
+        let (down, up) = key('a');
+        assert!(tap(&mut state, start, &[0]).is_empty());
+        // Played after the window ran out, but before anything expired it
+        assert_eq!(tap(&mut state, start, &[400]), vec![vec![down, up]]);
+        assert_eq!(state.next_expiry(), Some(start + ms(700)));
+    }
+
+    #[test]
+    fn taps_without_a_mapping_repeat_the_tap() {
+        let mut state = GestureState::new();
+        let start = Instant::now();
+        let mut mapping = multi_tap_mapping();
+        mapping.taps.retain(|multi_tap| multi_tap.count() == 3);
+        for at in &[0, 100] {
+            state.note_on(
+                "kbd",
+                0,
+                MidiNote::C4,
+                100,
+                mapping.clone(),
+                start + ms(*at),
+            );
+            state.note_off("kbd", 0, MidiNote::C4, start + ms(at + 50));
+        }
+        let (down, up) = key('a');
+        assert_eq!(
+            events(state.expire(start + ms(400))),
+            vec![vec![down.clone(), up.clone(), down, up]]
+        );
+    }
+}
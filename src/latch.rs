@@ -0,0 +1,162 @@
+use crate::notemappings::KbdKey;
+
+#[derive(Clone, Debug, PartialEq)]
+enum State {
+    Off,
+    /// Latched, but waiting for the next NoteMod to pick the modifier
+    Waiting,
+    /// Latched to a modifier, or to no modifier at all
+    On(Option<KbdKey>),
+}
+
+/// Holds the note modifier steady, typically while the sustain pedal is
+/// down.
+///
+/// While latched, a NoteMod asking for the latched modifier, or for none,
+/// keeps the latched one, so notes from unmodified octaves don't release it
+/// (and wait for it to be pressed again) in between.  A NoteMod asking for
+/// a different modifier still switches to it, so its note types the right
+/// character, and the latched modifier comes back with the next note that
+/// doesn't need another one.
+pub struct NoteModLatch {
+    state: State,
+    /// The device that latched, while latched
+    device: String,
+}
+
+impl Default for NoteModLatch {
+    fn default() -> NoteModLatch {
+        NoteModLatch {
+            state: State::Off,
+            device: String::new(),
+        }
+    }
+}
+
+impl NoteModLatch {
+    pub fn new() -> NoteModLatch {
+        NoteModLatch::default()
+    }
+
+    /// Latch on behalf of `device`, to `modifier` if it's given, or else
+    /// to whichever modifier the next NoteMod asks for.
+    pub fn latch(&mut self, device: &str, modifier: Option<KbdKey>) {
+        self.state = match modifier {
+            Some(modifier) => State::On(Some(modifier)),
+            None => State::Waiting,
+        };
+        self.device = device.to_owned();
+    }
+
+    /// Go back to letting each NoteMod pick its own modifier, if `device`
+    /// is the one that latched.  Returns `true` if a modifier had been
+    /// latched, which should now be released.
+    pub fn unlatch(&mut self, device: &str) -> bool {
+        if self.device != device {
+            return false;
+        }
+        let latched = matches!(self.state, State::On(_));
+        self.state = State::Off;
+        latched
+    }
+
+    /// The modifier to use for a NoteMod asking for `requested`.  If the
+    /// latch is waiting for a modifier, this one is latched.
+    pub fn note_mod(&mut self, requested: &Option<KbdKey>) -> Option<KbdKey> {
+        match self.state {
+            State::Off => requested.clone(),
+            State::Waiting => {
+                self.state = State::On(requested.clone());
+                requested.clone()
+            }
+            State::On(ref latched) => match *requested {
+                Some(ref k) if Some(k) != latched.as_ref() => requested.clone(),
+                _ => latched.clone(),
+            },
+        }
+    }
+
+    /// Unlatch if `device` latched.
+    pub fn clear_device(&mut self, device: &str) {
+        if self.device == device {
+            self.state = State::Off;
+        }
+    }
+
+    /// Unlatch without releasing anything.
+    pub fn clear(&mut self) {
+        self.state = State::Off;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn off_passes_modifiers_through() {
+        let mut latch = NoteModLatch::new();
+        assert_eq!(latch.note_mod(&Some(KbdKey::Shift)), Some(KbdKey::Shift));
+        assert_eq!(latch.note_mod(&None), None);
+        assert!(!latch.unlatch("pedal"));
+    }
+
+    #[test]
+    fn latched_modifier_survives_notes_without_one() {
+        let mut latch = NoteModLatch::new();
+        latch.latch("pedal", Some(KbdKey::Shift));
+        assert_eq!(latch.note_mod(&None), Some(KbdKey::Shift));
+        assert_eq!(latch.note_mod(&Some(KbdKey::Shift)), Some(KbdKey::Shift));
+        assert!(latch.unlatch("pedal"));
+        assert_eq!(latch.note_mod(&None), None);
+    }
+
+    #[test]
+    fn other_modifiers_still_switch() {
+        let mut latch = NoteModLatch::new();
+        latch.latch("pedal", Some(KbdKey::Shift));
+        assert_eq!(
+            latch.note_mod(&Some(KbdKey::Control)),
+            Some(KbdKey::Control)
+        );
+        assert_eq!(latch.note_mod(&None), Some(KbdKey::Shift));
+    }
+
+    #[test]
+    fn waiting_latches_the_next_modifier() {
+        let mut latch = NoteModLatch::new();
+        latch.latch("pedal", None);
+        assert_eq!(latch.note_mod(&Some(KbdKey::Alt)), Some(KbdKey::Alt));
+        assert_eq!(latch.note_mod(&None), Some(KbdKey::Alt));
+        assert_eq!(latch.note_mod(&Some(KbdKey::Shift)), Some(KbdKey::Shift));
+        assert_eq!(latch.note_mod(&None), Some(KbdKey::Alt));
+    }
+
+    #[test]
+    fn waiting_can_latch_no_modifier() {
+        let mut latch = NoteModLatch::new();
+        latch.latch("pedal", None);
+        assert_eq!(latch.note_mod(&None), None);
+        assert!(latch.unlatch("pedal"));
+    }
+
+    #[test]
+    fn only_the_latching_device_unlatches() {
+        let mut latch = NoteModLatch::new();
+        latch.latch("pedal", Some(KbdKey::Shift));
+        assert!(!latch.unlatch("other pedal"));
+        assert_eq!(latch.note_mod(&None), Some(KbdKey::Shift));
+        assert!(latch.unlatch("pedal"));
+        assert_eq!(latch.note_mod(&None), None);
+    }
+
+    #[test]
+    fn clear_device_only_unlatches_its_own_latch() {
+        let mut latch = NoteModLatch::new();
+        latch.latch("pedal", Some(KbdKey::Shift));
+        latch.clear_device("keys");
+        assert_eq!(latch.note_mod(&None), Some(KbdKey::Shift));
+        latch.clear_device("pedal");
+        assert_eq!(latch.note_mod(&None), None);
+    }
+}
@@ -2,21 +2,52 @@
 
 use std::collections::HashMap;
 use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead};
+use std::panic;
+use std::sync::{mpsc, Arc, TryLockError};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, Arg, SubCommand};
 
 use midir::{Ignore, MidiInput, MidiInputConnection};
 
 pub mod midi;
-use midi::{MidiEvent, MidiMessage, MidiNote};
+use midi::{MidiEvent, MidiMessage, MidiNote, MidiParser, PITCH_BEND_CENTER};
 
 pub mod appstate;
 use appstate::AppState;
 
+pub mod backend;
+use backend::PrintBackend;
+
+#[cfg(target_os = "linux")]
+pub mod uinput;
+#[cfg(target_os = "linux")]
+use uinput::UinputBackend;
+
+pub mod mappingfile;
+use mappingfile::MappingsError;
+
+pub mod chords;
+use chords::ChordOutput;
+
+pub mod gestures;
+use gestures::GestureOutput;
+
+pub mod scheduler;
+use scheduler::Source;
+
+pub mod transpose;
+
+pub mod latch;
+
 pub mod notemappings;
-use notemappings::{Event, KbdKey, NoteMapping, NoteMappings};
+use notemappings::{
+    ContinuousSource, ControlMapping, Event, KbdKey, Layer, MouseAxis, NoteMapping, NoteMappings,
+    Transport, VelocityLayer, DEFAULT_LAYER,
+};
 
 #[cfg(feature = "debug")]
 use std::fmt::Write;
@@ -24,14 +55,23 @@ use std::fmt::Write;
 /// The amount of time to wait for a keyboard modifier to stick
 const MOD_DELAY_MS: u64 = 150;
 
-/// The amount of time to wait for a keydown event to stick
-const KEY_DELAY_MS: u64 = 40;
+/// The controller number of the sustain pedal
+const SUSTAIN_CONTROLLER: u8 = 64;
+
+/// Notes struck at least this hard are typed with Shift held
+const SHIFT_VELOCITY: u8 = 100;
 
-/// The amount of time required for system events, such as Esc
-const SYS_DELAY_MS: u64 = 400;
+/// The ways keys can be sent, for the --output option
+#[cfg(target_os = "linux")]
+const OUTPUTS: &[&str] = &["enigo", "print", "uinput"];
+#[cfg(not(target_os = "linux"))]
+const OUTPUTS: &[&str] = &["enigo", "print"];
 
-/// A small delay required when switching between octaves.
-const OCTAVE_DELAY_MS: u64 = 10;
+/// How often continuous inputs such as the pitch wheel are sampled
+const CONTINUOUS_TICK_MS: u64 = 10;
+
+/// How often held keys are checked to see if they're stuck
+const WATCHDOG_TICK_MS: u64 = 100;
 
 fn main() {
     let matches = App::new("Midi Perform")
@@ -55,9 +95,43 @@ fn main() {
             Arg::with_name("mappings")
                 .short("f")
                 .long("mappings")
-                .help("Load a mappings file (line format: note channel keydown keyup)")
+                .help("Load a mappings file (line format: note channel: on-events | off-events)")
                 .value_name("MAPPINGS"),
         )
+        .arg(
+            Arg::with_name("check")
+                .short("c")
+                .long("check")
+                .help("Check a mappings file for errors without connecting to any devices")
+                .value_name("MAPPINGS"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .help("How to send keys: \"enigo\" presses them, \"print\" just prints them, and \"uinput\" uses a virtual Linux keyboard")
+                .value_name("OUTPUT")
+                .possible_values(OUTPUTS)
+                .default_value("enigo"),
+        )
+        .arg(
+            Arg::with_name("print-mappings")
+                .short("p")
+                .long("print-mappings")
+                .help("Print the loaded mappings in the mappings file format and exit"),
+        )
+        .subcommand(
+            SubCommand::with_name("learn")
+                .about("Build a mappings file by playing each note and typing the keys it should press")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("The mappings file to add to")
+                        .required(true),
+                ),
+        )
+        .subcommand(SubCommand::with_name("monitor").about(
+            "Print every MIDI message and the mapping it would fire, without pressing any keys",
+        ))
         .get_matches();
 
     if matches.is_present("list") {
@@ -66,83 +140,432 @@ fn main() {
     }
     let device_name = matches.value_of("device");
     let mappings_file = matches.value_of("mappings");
-    run(device_name, mappings_file).unwrap();
+    if matches.subcommand_matches("monitor").is_some() {
+        if let Err(e) = monitor(device_name, mappings_file) {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(learn_matches) = matches.subcommand_matches("learn") {
+        if let Err(e) = learn(device_name, learn_matches.value_of("FILE").unwrap()) {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(filename) = matches.value_of("check") {
+        if !check_mappings(filename) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if matches.is_present("print-mappings") {
+        print_mappings(mappings_file).expect("unable to print mappings");
+        return;
+    }
+    let app_state = match matches.value_of("output") {
+        Some("print") => AppState::with_backend(Box::new(PrintBackend)),
+        #[cfg(target_os = "linux")]
+        Some("uinput") => match UinputBackend::create() {
+            Ok(backend) => AppState::with_backend(Box::new(backend)),
+            Err(e) => {
+                println!("Unable to create a uinput keyboard: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => AppState::new(),
+    };
+    if let Err(e) = run(device_name, mappings_file, app_state) {
+        println!("{}", e);
+        std::process::exit(1);
+    }
 }
 
-/// This function is called for every message that gets passed in.
-fn midi_callback(_timestamp_us: u64, raw_message: &[u8], app_state: &AppState) {
-    let mut keygen = app_state.keygen().lock().unwrap();
+/// This function is called for every packet that gets passed in, and
+/// decodes it into individual messages.
+fn midi_callback(
+    timestamp_us: u64,
+    raw_message: &[u8],
+    parser: &mut MidiParser,
+    device: &str,
+    app_state: &AppState,
+) {
+    for msg in parser.parse(raw_message) {
+        match msg {
+            Ok(msg) => handle_message(timestamp_us, device, msg, app_state),
+            Err(_e) => {
+                #[cfg(feature = "debug")]
+                println!("Unable to decode message: {:?}", _e);
+            }
+        }
+    }
 
-    if let Ok(msg) = MidiMessage::new(raw_message) {
-        match app_state
-            .mappings()
-            .lock()
-            .unwrap()
-            .find(*msg.note(), msg.channel(), None)
-        {
-            Some(note_mapping) => {
-                let sequence = match *msg.event() {
-                    MidiEvent::NoteOn => &note_mapping.on,
-                    MidiEvent::NoteOff => &note_mapping.off,
+    #[cfg(feature = "debug")]
+    {
+        let mut s = String::new();
+        for &byte in raw_message {
+            write!(&mut s, "{:X} ", byte).expect("Unable to write");
+        }
+        println!("Raw message data: {}", s);
+    }
+}
+
+/// This function is called for every message that gets decoded.
+/// Sequences are handed to the scheduler, so this never waits on a delay.
+fn handle_message(_timestamp_us: u64, device: &str, msg: MidiMessage, app_state: &AppState) {
+    match *msg.event() {
+        MidiEvent::Start | MidiEvent::Continue | MidiEvent::Stop => {
+            let transport = Transport::from_event(msg.event()).unwrap();
+            let transport_mapping = app_state
+                .mappings()
+                .lock()
+                .unwrap()
+                .find_transport(transport);
+            if let Some(transport_mapping) = transport_mapping {
+                app_state.scheduler().schedule(
+                    Source::Transport(transport),
+                    device,
+                    &transport_mapping.on,
+                    None,
+                );
+            }
+        }
+
+        // Notes are transposed, then go through chord detection, which may
+        // hold them back for a moment.
+        MidiEvent::NoteOn | MidiEvent::NoteOff => {
+            let note = msg.note().expect("note event without a note");
+            let note = {
+                let mut transposer = app_state.transposer().lock().unwrap();
+                let transposed = if *msg.event() == MidiEvent::NoteOn {
+                    transposer.note_on(msg.channel(), note)
+                } else {
+                    transposer.note_off(msg.channel(), note)
                 };
+                match transposed {
+                    Some(transposed) => transposed,
+                    None => {
+                        println!(
+                            "{:?} is out of range when transposed by {} semitones",
+                            note,
+                            transposer.offset()
+                        );
+                        return;
+                    }
+                }
+            };
+            let chords = app_state.mappings().lock().unwrap().chords(msg.channel());
+            let outputs = {
+                let mut chord_state = app_state.chords().lock().unwrap();
+                if *msg.event() == MidiEvent::NoteOn {
+                    chord_state.note_on(
+                        device,
+                        msg.channel(),
+                        note,
+                        msg.velocity(),
+                        Instant::now(),
+                        &chords,
+                    )
+                } else {
+                    chord_state.note_off(device, msg.channel(), note, Instant::now())
+                }
+            };
+            if app_state.chords().lock().unwrap().next_expiry().is_some() {
+                app_state.expiry().notify();
+            }
+            play_chord_outputs(app_state, outputs);
+        }
 
-                //println!("Found note mapping: {:?} for event {:?}, running sequence {:?}", note_mapping, msg.event(), sequence);
-                for event in sequence {
-                    match *event {
-                        notemappings::Event::Delay(msecs) => {
-                            thread::sleep(Duration::from_millis(msecs))
-                        }
-                        notemappings::Event::KeyDown(ref k) => {
-                            keygen.key_down(&k);
-                        }
-                        notemappings::Event::KeyUp(ref k) => {
-                            keygen.key_up(&k);
-                        }
+        // Controllers send a stream of values, so only fire a sequence
+        // when the value crosses the mapping's threshold.  The value is also
+        // recorded for any continuous mapping, as for the pitch wheel.
+        MidiEvent::ControlChange { controller, value } => {
+            let amount = ((f32::from(value) - 64.0) / 63.0).max(-1.0);
+            app_state.amounts().lock().unwrap().insert(
+                (msg.channel(), ContinuousSource::Control(controller)),
+                amount,
+            );
 
-                        // For NoteMod, which goes at the top of a note, see if we need to change
-                        // the current set of modifiers.  If so, pause a short while.
-                        // This enables fast switching between notes in the same octave, where no
-                        // keychange is required.
-                        notemappings::Event::NoteMod(ref kopt) => {
-                            let mut changes = 0;
-                            let key_mods = vec![KbdKey::Shift, KbdKey::Control];
-                            if let Some(ref k) = *kopt {
-                                for key_mod in key_mods {
-                                    if &key_mod == k {
-                                        if keygen.key_down(&key_mod) {
-                                            changes += 1;
-                                        }
-                                    } else if keygen.key_up(&key_mod) {
-                                        changes += 1;
-                                    }
-                                }
-                            } else {
-                                for key_mod in key_mods {
-                                    if keygen.key_up(&key_mod) {
-                                        changes += 1;
-                                    }
-                                }
-                            }
-                            if changes > 0 {
-                                thread::sleep(Duration::from_millis(OCTAVE_DELAY_MS));
-                            }
-                        }
+            let control_mapping = app_state
+                .mappings()
+                .lock()
+                .unwrap()
+                .find_control(controller, msg.channel());
+            if let Some(control_mapping) = control_mapping {
+                let is_on = control_mapping.is_on(value);
+                let was_on = app_state
+                    .controls()
+                    .lock()
+                    .unwrap()
+                    .insert((msg.channel(), controller), is_on)
+                    .unwrap_or(false);
+                let source = Source::Control(msg.channel(), controller);
+                let max_hold = control_mapping.max_hold.map(Duration::from_millis);
+                if is_on && !was_on {
+                    app_state
+                        .scheduler()
+                        .schedule(source, device, &control_mapping.on, max_hold);
+                } else if !is_on && was_on {
+                    app_state
+                        .scheduler()
+                        .schedule(source, device, &control_mapping.off, max_hold);
+                }
+            }
+        }
+
+        MidiEvent::ProgramChange { program } => {
+            let mut keygen = app_state.keygen().lock().unwrap();
+            let layer = {
+                let mut mappings = app_state.mappings().lock().unwrap();
+                mappings.select_program(program);
+                mappings.active_layer().to_owned()
+            };
+            let released = app_state.reset_notes(&mut keygen, None);
+            println!(
+                "Program {} selected layer {} ({} keys released)",
+                program, layer, released
+            );
+        }
+
+        // Continuous inputs just record their latest amount, and
+        // `repeat_continuous()` turns that into keypresses.
+        MidiEvent::PitchBend { value } => {
+            let amount =
+                (f32::from(value) - f32::from(PITCH_BEND_CENTER)) / f32::from(PITCH_BEND_CENTER);
+            app_state
+                .amounts()
+                .lock()
+                .unwrap()
+                .insert((msg.channel(), ContinuousSource::PitchBend), amount);
+        }
+        MidiEvent::ChannelPressure { pressure } => {
+            let amount = f32::from(pressure) / 127.0;
+            app_state
+                .amounts()
+                .lock()
+                .unwrap()
+                .insert((msg.channel(), ContinuousSource::ChannelPressure), amount);
+        }
+
+        // Clock, SysEx and the other system messages don't map to anything,
+        // but are useful to see when debugging a device.
+        ref _other => {
+            #[cfg(feature = "debug")]
+            println!("Unmapped message: {}", _other);
+        }
+    }
+}
+
+/// Run whatever chord detection decided on.
+fn play_chord_outputs(app_state: &AppState, outputs: Vec<ChordOutput>) {
+    for output in outputs {
+        match output {
+            ChordOutput::Note {
+                device,
+                channel,
+                note,
+                on,
+                velocity,
+            } => play_note(app_state, &device, channel, note, on, velocity),
+            ChordOutput::Chord {
+                device,
+                mapping,
+                on,
+            } => {
+                let sequence = if on { &mapping.on } else { &mapping.off };
+                app_state.scheduler().schedule(
+                    Source::Chord(device.clone(), mapping.channel(), mapping.notes()[0]),
+                    &device,
+                    sequence,
+                    None,
+                );
+            }
+        }
+    }
+}
+
+/// Run the mapping for a single note being pressed or released.
+fn play_note(
+    app_state: &AppState,
+    device: &str,
+    channel: u8,
+    note: MidiNote,
+    on: bool,
+    velocity: u8,
+) {
+    if !on {
+        let outputs =
+            app_state
+                .gestures()
+                .lock()
+                .unwrap()
+                .note_off(device, channel, note, Instant::now());
+        if let Some(outputs) = outputs {
+            app_state.expiry().notify();
+            play_gesture_outputs(app_state, outputs);
+            return;
+        }
+    }
+
+    let note_mapping = app_state
+        .mappings()
+        .lock()
+        .unwrap()
+        .find(note, channel, Some(device));
+    match note_mapping {
+        // Notes with a long press or multiple taps wait to see how
+        // they're played.
+        Some(note_mapping) if on && note_mapping.has_gestures() => {
+            let outputs = app_state.gestures().lock().unwrap().note_on(
+                device,
+                channel,
+                note,
+                velocity,
+                note_mapping,
+                Instant::now(),
+            );
+            app_state.expiry().notify();
+            play_gesture_outputs(app_state, outputs);
+        }
+        Some(note_mapping) => {
+            // Remember how hard the note was struck, so its release
+            // uses the same velocity layer as its press.
+            let mut velocities = app_state.velocities().lock().unwrap();
+            let sequence = if on {
+                velocities.insert((device.to_owned(), channel, note), velocity);
+                note_mapping.on_sequence(velocity)
+            } else {
+                let velocity = velocities
+                    .remove(&(device.to_owned(), channel, note))
+                    .unwrap_or(0);
+                note_mapping.off_sequence(velocity)
+            };
+            drop(velocities);
+
+            //println!("Found note mapping: {:?} for event {:?}, running sequence {:?}", note_mapping, msg.event(), sequence);
+            app_state.scheduler().schedule(
+                Source::Note(device.to_owned(), channel, note),
+                device,
+                sequence,
+                note_mapping.max_hold.map(Duration::from_millis),
+            );
+        }
+        _ => {
+            println!("No note mapping for {:?} @ {:?}", note, channel);
+        }
+    }
+}
+
+/// Run the sequences that tap and long press detection decided on.
+fn play_gesture_outputs(app_state: &AppState, outputs: Vec<GestureOutput>) {
+    for output in outputs {
+        app_state.scheduler().schedule(
+            Source::Note(output.device.clone(), output.channel, output.note),
+            &output.device,
+            &output.events,
+            output.max_hold.map(Duration::from_millis),
+        );
+    }
+}
+
+/// Play held back notes once it's clear they aren't part of a chord, run
+/// long press sequences once notes have been held for long enough, and
+/// multiple tap sequences once no more taps can follow.  In between, sleep
+/// until the next of these is due, or until a note is played.
+fn expire_held_notes(app_state: AppState) {
+    loop {
+        let now = Instant::now();
+        let outputs = app_state.chords().lock().unwrap().expire(now);
+        play_chord_outputs(&app_state, outputs);
+        let outputs = app_state.gestures().lock().unwrap().expire(now);
+        play_gesture_outputs(&app_state, outputs);
+
+        let chords = app_state.chords().lock().unwrap().next_expiry();
+        let gestures = app_state.gestures().lock().unwrap().next_expiry();
+        let next = match (chords, gestures) {
+            (Some(chords), Some(gestures)) => Some(chords.min(gestures)),
+            (chords, gestures) => chords.or(gestures),
+        };
+        app_state.expiry().wait_until(next);
+    }
+}
+
+/// Turn continuous inputs into repeated keypresses.  Each mapping builds up
+/// "owed" presses at a rate proportional to how far its input is from rest,
+/// and a key is tapped whenever a whole press is owed.  Mouse mappings
+/// work the same way, but owe pixels or scroll steps in either direction.
+fn repeat_continuous(app_state: AppState) {
+    let tick = Duration::from_millis(CONTINUOUS_TICK_MS);
+    let mut owed: HashMap<(u8, ContinuousSource), f32> = HashMap::new();
+    loop {
+        thread::sleep(tick);
+
+        let mappings = app_state.mappings().lock().unwrap().continuous();
+        let amounts = app_state.amounts().lock().unwrap().clone();
+        for mapping in mappings {
+            let input = (mapping.channel(), mapping.source());
+            let amount = amounts.get(&input).cloned().unwrap_or(0.0);
+
+            if let Some(axis) = mapping.mouse {
+                if mapping.is_at_rest(amount) {
+                    owed.remove(&input);
+                    continue;
+                }
+                let distance = owed.entry(input).or_insert(0.0);
+                *distance += amount * mapping.rate() * tick.as_secs_f32();
+                let steps = distance.trunc();
+                if steps != 0.0 {
+                    *distance -= steps;
+                    // Positive amounts go right or up, and the backend counts
+                    // down as positive.
+                    let steps = steps as i32;
+                    let mut keygen = app_state.keygen().lock().unwrap();
+                    match axis {
+                        MouseAxis::MoveX => keygen.mouse_move(steps, 0),
+                        MouseAxis::MoveY => keygen.mouse_move(0, -steps),
+                        MouseAxis::ScrollX => keygen.scroll(steps, 0),
+                        MouseAxis::ScrollY => keygen.scroll(0, -steps),
                     }
                 }
+                continue;
             }
-            _ => {
-                println!("No note mapping for {:?} @ {:?}", msg.note(), msg.channel());
+
+            let key = match mapping.key_for(amount) {
+                Some(key) => key,
+                None => {
+                    owed.remove(&input);
+                    continue;
+                }
+            };
+
+            // Press immediately when the input first leaves rest, so a quick
+            // flick of the wheel always produces at least one keypress.
+            let presses = owed.entry(input).or_insert(1.0);
+            if *presses >= 1.0 {
+                *presses -= 1.0;
+                let mut keygen = app_state.keygen().lock().unwrap();
+                keygen.key_down(key);
+                keygen.key_up(key);
             }
+            *presses += amount.abs() * mapping.rate() * tick.as_secs_f32();
         }
     }
+}
 
-    #[cfg(feature = "debug")]
-    {
-        let mut s = String::new();
-        for &byte in raw_message {
-            write!(&mut s, "{:X} ", byte).expect("Unable to write");
+/// Release keys that have been held for longer than their mapping allows,
+/// which usually means a note off message went missing.
+fn watch_stuck_keys(app_state: AppState) {
+    loop {
+        thread::sleep(Duration::from_millis(WATCHDOG_TICK_MS));
+        let stuck = app_state.keygen().lock().unwrap().release_stuck();
+        for (key, held_for) in stuck {
+            println!(
+                "Warning: released {} after it was held for {}ms",
+                key,
+                held_for.as_millis()
+            );
         }
-        println!("Unhandled message for data: {}", s);
     }
 }
 
@@ -172,61 +595,243 @@ fn generate_old_mappings(mappings: &mut NoteMappings) {
         note_mapping_mid.on = NoteMapping::down_event(*key, None, None);
         note_mapping_mid.off = NoteMapping::up_event(*key, None, None);
 
+        let mut hard_layer = VelocityLayer::new(SHIFT_VELOCITY, 127);
+        hard_layer.on = NoteMapping::down_event(*key, Some(KbdKey::Shift), None);
+        hard_layer.off = NoteMapping::up_event(*key, Some(KbdKey::Shift), None);
+        note_mapping_mid.velocity_layers.push(hard_layer);
+
         mappings.add(note_mapping_lo);
         mappings.add(note_mapping_mid);
     }
 
     // Add pad buttons on the top of my keyboard, which are on channel 9.
-    let pads = vec!['z', 'x', 'c', 'v', 'b', 'n', 'm', ','];
+    // These live in the default layer so they're available from any layer.
+    let pads = [DEFAULT_LAYER, "nav"];
     for (pad_idx, pad) in pads.iter().enumerate() {
-        let seq = vec![
-            Event::NoteMod(None), // Ensure no modifier keys are pressed at the start
-            // Press Escape twice to clear any dialogs, and to potentially
-            // exit the current Perform session.
-            Event::KeyDown(KbdKey::Escape),
-            Event::Delay(KEY_DELAY_MS),
-            Event::KeyUp(KbdKey::Escape),
-            Event::Delay(SYS_DELAY_MS),
-            // Hold Control, Alt, and Shift.
-            Event::KeyDown(KbdKey::Control),
-            Event::KeyDown(KbdKey::Alt),
-            Event::KeyDown(KbdKey::Shift),
-            // Let the modifier keys get registered
-            Event::Delay(MOD_DELAY_MS),
-            Event::KeyDown(KbdKey::Layout(*pad)),
-            Event::Delay(KEY_DELAY_MS),
-            Event::KeyUp(KbdKey::Layout(*pad)),
-            Event::Delay(MOD_DELAY_MS),
-            Event::KeyUp(KbdKey::Shift),
-            Event::KeyUp(KbdKey::Alt),
-            Event::KeyUp(KbdKey::Control),
-        ];
-
         let mut pad_mapping = NoteMapping::new(
             MidiNote::new(pad_idx as u8 + 40).expect("Invalid note index"),
             9,
             None,
         );
-        pad_mapping.on = seq;
+        pad_mapping.on = vec![Event::SelectLayer((*pad).to_owned())];
         mappings.add(pad_mapping);
     }
-}
 
-fn run(midi_name: Option<&str>, mappings_file: Option<&str>) -> Result<(), Box<dyn Error>> {
-    let mut midi_ports: HashMap<String, MidiInputConnection<()>> = HashMap::new();
-    let app_state = AppState::new();
+    // The next pads shift the keys down an octave, up an octave, and back.
+    let octave_pads = [
+        Event::Transpose(-12),
+        Event::Transpose(12),
+        Event::TransposeReset,
+    ];
+    for (pad_idx, event) in octave_pads.iter().enumerate() {
+        let mut pad_mapping = NoteMapping::new(
+            MidiNote::new(pad_idx as u8 + 40 + pads.len() as u8).expect("Invalid note index"),
+            9,
+            None,
+        );
+        pad_mapping.on = vec![event.clone()];
+        mappings.add(pad_mapping);
+    }
 
+    // Holding the sustain pedal keeps the modifier of the first note played,
+    // so a run of notes can be typed without switching octave in between.
+    let mut sustain = ControlMapping::new(SUSTAIN_CONTROLLER, 0, 64);
+    sustain.on = vec![Event::Latch(None)];
+    sustain.off = vec![Event::Unlatch];
+    mappings.add_control(sustain);
+
+    // A navigation layer, laid out over the white keys starting at C2.
+    let nav_keys = [
+        KbdKey::LeftArrow,
+        KbdKey::DownArrow,
+        KbdKey::UpArrow,
+        KbdKey::RightArrow,
+        KbdKey::Home,
+        KbdKey::PageUp,
+        KbdKey::PageDown,
+        KbdKey::Backspace,
+        KbdKey::Return,
+        KbdKey::Tab,
+        KbdKey::Escape,
+        KbdKey::Space,
+    ];
+    let white_keys = [0, 2, 4, 5, 7, 9, 11];
+    mappings.add_layer(Layer::new("nav", Some(1)));
+    for (key_idx, key) in nav_keys.iter().enumerate() {
+        let offset = (key_idx / 7) * 12 + white_keys[key_idx % 7];
+        let mut nav_mapping = NoteMapping::new(
+            MidiNote::new(MidiNote::C2.index() + offset as u8).expect("Invalid note index"),
+            0,
+            None,
+        );
+        nav_mapping.on = vec![Event::NoteMod(None), Event::KeyDown(key.clone())];
+        nav_mapping.off = vec![Event::KeyUp(key.clone())];
+        mappings.add(nav_mapping);
+    }
+}
+
+/// Load mappings from a file, or generate the built-in ones if no file is given.
+fn load_mappings(
+    mappings: &mut NoteMappings,
+    mappings_file: Option<&str>,
+) -> Result<(), MappingsError> {
     match mappings_file {
-        Some(filename) => app_state
-            .mappings()
-            .lock()
-            .unwrap()
-            .import(filename)
-            .unwrap(),
-        None => generate_old_mappings(&mut app_state.mappings().lock().unwrap()),
+        Some(filename) => mappings.import(filename),
+        None => {
+            generate_old_mappings(mappings);
+            Ok(())
+        }
+    }
+}
+
+/// Report every problem in a mappings file.
+/// Returns `true` if the file has no problems.
+fn check_mappings(filename: &str) -> bool {
+    match NoteMappings::new().import(filename) {
+        Ok(()) => {
+            println!("{}: no problems found", filename);
+            true
+        }
+        Err(MappingsError::Parse(errors)) => {
+            for error in &errors {
+                println!("{}", error);
+            }
+            println!("{}: {} problem(s) found", filename, errors.len());
+            false
+        }
+        Err(e) => {
+            println!("{}: {}", filename, e);
+            false
+        }
+    }
+}
+
+fn print_mappings(mappings_file: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let mut mappings = NoteMappings::new();
+    load_mappings(&mut mappings, mappings_file)?;
+    mappingfile::write(&mappings, &mut std::io::stdout())?;
+    Ok(())
+}
+
+/// The time a file was last modified, if it can be read.
+fn modified_time(filename: &str) -> Option<SystemTime> {
+    fs::metadata(filename).and_then(|m| m.modified()).ok()
+}
+
+/// Load a mappings file again and swap it in for the current mappings.
+/// If the file has problems, the current mappings are kept.
+fn reload_mappings(app_state: &AppState, filename: &str) {
+    let mut new_mappings = NoteMappings::new();
+    if let Err(e) = new_mappings.import(filename) {
+        println!("Not reloading {}, keeping the current mappings:", filename);
+        println!("{}", e);
+        return;
+    }
+
+    // Hold the keygen while swapping so no message is handled halfway
+    // through, and release everything so no key is left stuck down by a
+    // mapping that no longer exists.
+    let mut keygen = app_state.keygen().lock().unwrap();
+    *app_state.mappings().lock().unwrap() = new_mappings;
+    let released = app_state.reset_notes(&mut keygen, None);
+    app_state.controls().lock().unwrap().clear();
+    app_state.transposer().lock().unwrap().clear();
+    println!("Reloaded {} ({} keys released)", filename, released);
+}
+
+/// Stop running sequences and release every held key.
+/// Returns the number of keys that were released.
+fn release_keys(app_state: &AppState) -> u32 {
+    app_state.scheduler().clear();
+    // An earlier panic may have poisoned the keygen, but its keys still
+    // need releasing.
+    let mut keygen = match app_state.keygen().lock() {
+        Ok(keygen) => keygen,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    keygen.key_reset()
+}
+
+/// Release every held key after a panic, then exit, since the panicking
+/// thread may have been one that's needed to type anything again.
+///
+/// The scheduler is left alone, as the panicking thread may be holding its
+/// queue.  Instead the keygen is kept locked until the process exits, so
+/// no sequence can press another key in between.
+fn release_keys_after_panic(app_state: &AppState) -> ! {
+    // The panic may have poisoned the keygen, but its keys still need
+    // releasing.
+    let mut keygen = match app_state.keygen().lock() {
+        Ok(keygen) => keygen,
+        Err(poisoned) => poisoned.into_inner(),
     };
+    let released = keygen.key_reset();
+    println!("Released {} keys after a panic, exiting", released);
+    std::process::exit(1);
+}
+
+/// Make sure no keys are left stuck down when we're interrupted or
+/// terminated, or when any thread panics.
+fn release_keys_on_exit(app_state: &AppState) {
+    let app_state_thr = app_state.clone();
+    ctrlc::set_handler(move || {
+        let released = release_keys(&app_state_thr);
+        println!("Exiting ({} keys released)", released);
+        std::process::exit(0);
+    })
+    .expect("unable to set signal handler");
+
+    let app_state_thr = app_state.clone();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        // The panicking thread may be the one holding the keygen, in which
+        // case it's only let go once this hook returns and the thread
+        // unwinds.  Wait for that on another thread.
+        let busy = matches!(
+            app_state_thr.keygen().try_lock(),
+            Err(TryLockError::WouldBlock)
+        );
+        if busy {
+            let app_state_thr = app_state_thr.clone();
+            thread::spawn(move || release_keys_after_panic(&app_state_thr));
+        } else {
+            release_keys_after_panic(&app_state_thr);
+        }
+    }));
+}
+
+fn run(
+    midi_name: Option<&str>,
+    mappings_file: Option<&str>,
+    app_state: AppState,
+) -> Result<(), Box<dyn Error>> {
+    let mut midi_ports: HashMap<String, MidiInputConnection<MidiParser>> = HashMap::new();
+
+    load_mappings(&mut app_state.mappings().lock().unwrap(), mappings_file)?;
+    let mut mappings_modified = mappings_file.and_then(modified_time);
+
+    release_keys_on_exit(&app_state);
+
+    let app_state_thr = app_state.clone();
+    thread::spawn(move || repeat_continuous(app_state_thr));
+    let app_state_thr = app_state.clone();
+    thread::spawn(move || app_state_thr.scheduler().run(&app_state_thr));
+    let app_state_thr = app_state.clone();
+    thread::spawn(move || watch_stuck_keys(app_state_thr));
+    let app_state_thr = app_state.clone();
+    thread::spawn(move || expire_held_notes(app_state_thr));
 
     loop {
+        // Pick up any changes to the mappings file.
+        if let Some(filename) = mappings_file {
+            let modified = modified_time(filename);
+            if modified != mappings_modified {
+                mappings_modified = modified;
+                reload_mappings(&app_state, filename);
+            }
+        }
+
         let ports = MidiInput::new("perform-count")
             .expect("Couldn't create midi input")
             .ports();
@@ -256,13 +861,14 @@ fn run(midi_name: Option<&str>, mappings_file: Option<&str>) -> Result<(), Box<d
                     // This device is new.
                     midi_in.ignore(Ignore::None);
                     let app_state_thr = app_state.clone();
+                    let device = name.clone();
                     match midi_in.connect(
                         &port,
                         "key monitor",
-                        move |ts, raw_msg, _ignored| {
-                            midi_callback(ts, raw_msg, &app_state_thr);
+                        move |ts, raw_msg, parser| {
+                            midi_callback(ts, raw_msg, parser, &device, &app_state_thr);
                         },
-                        (),
+                        MidiParser::new(),
                     ) {
                         Err(reason) => println!("Unable to connect to device: {:?}", reason),
                         Ok(conn) => {
@@ -281,13 +887,250 @@ fn run(midi_name: Option<&str>, mappings_file: Option<&str>) -> Result<(), Box<d
             }
         }
         for name in to_delete {
-            println!("Disconnected from {}", name);
+            // Close the connection first, so nothing more arrives from the
+            // device after its keys have been released.
             midi_ports.remove(&name);
+            app_state.chords().lock().unwrap().clear_device(&name);
+            app_state
+                .velocities()
+                .lock()
+                .unwrap()
+                .retain(|(device, _, _), _| *device != name);
+            app_state.gestures().lock().unwrap().clear_device(&name);
+            app_state.latch().lock().unwrap().clear_device(&name);
+            app_state.scheduler().clear_device(&name);
+            let released = app_state.keygen().lock().unwrap().release_device(&name);
+            println!("Disconnected from {} ({} keys released)", name, released);
         }
         thread::sleep(Duration::from_secs(1));
     }
 }
 
+/// Connect to every device that's currently available, or just to
+/// `midi_name` if it's given.  `callback` is called with the name of the
+/// device, the timestamp and each decoded message, and each device gets
+/// its own copy of it.
+fn connect_devices<F>(
+    midi_name: Option<&str>,
+    callback: F,
+) -> Result<Vec<MidiInputConnection<MidiParser>>, Box<dyn Error>>
+where
+    F: FnMut(&str, u64, MidiMessage) + Clone + Send + 'static,
+{
+    let mut connections = vec![];
+    let midi_in = MidiInput::new("listen")?;
+    for port in midi_in.ports() {
+        let name = midi_in.port_name(&port)?;
+        if let Some(target_name) = midi_name {
+            if target_name != name {
+                continue;
+            }
+        }
+        let mut port_in = MidiInput::new("listen")?;
+        port_in.ignore(Ignore::None);
+        let mut callback = callback.clone();
+        let device = name.clone();
+        match port_in.connect(
+            &port,
+            "key listener",
+            move |ts, raw_msg, parser: &mut MidiParser| {
+                for msg in parser.parse(raw_msg).into_iter().flatten() {
+                    callback(&device, ts, msg);
+                }
+            },
+            MidiParser::new(),
+        ) {
+            Err(reason) => println!("Unable to connect to device: {:?}", reason),
+            Ok(conn) => {
+                println!("Listening to {}", name);
+                connections.push(conn);
+            }
+        }
+    }
+    if connections.is_empty() {
+        return Err("No MIDI devices to listen to".into());
+    }
+    Ok(connections)
+}
+
+/// Print every message that arrives, along with the sequence it would
+/// run, without pressing any keys.
+fn monitor(midi_name: Option<&str>, mappings_file: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let mut mappings = NoteMappings::new();
+    load_mappings(&mut mappings, mappings_file)?;
+    let mappings = Arc::new(mappings);
+
+    // The velocity each held note was struck with and when, so its release
+    // shows the sequence from the same velocity layer as its press, and
+    // whether it was a long press.
+    let mut presses: HashMap<(u8, MidiNote), (u8, u64)> = HashMap::new();
+    let _connections = connect_devices(midi_name, move |device, ts, msg| {
+        let note = match msg.note() {
+            Some(note) => format!("{:?}", note),
+            None => "-".to_owned(),
+        };
+        let fires = match *msg.event() {
+            MidiEvent::NoteOn | MidiEvent::NoteOff => {
+                let note = msg.note().unwrap();
+                let key = (msg.channel(), note);
+                let (velocity, pressed_at) = if *msg.event() == MidiEvent::NoteOn {
+                    presses.insert(key, (msg.velocity(), ts));
+                    (msg.velocity(), ts)
+                } else {
+                    presses.remove(&key).unwrap_or((0, ts))
+                };
+                let fires = mappings
+                    .find(note, msg.channel(), Some(device))
+                    .map(|mapping| match (msg.event(), &mapping.long_press) {
+                        (MidiEvent::NoteOn, None) if !mapping.taps.is_empty() => format!(
+                            "{} (unless tapped again within {}ms)",
+                            event_list(mapping.on_sequence(velocity)),
+                            mapping.tap_window().unwrap()
+                        ),
+                        (MidiEvent::NoteOn, None) => event_list(mapping.on_sequence(velocity)),
+                        (_, None) => event_list(mapping.off_sequence(velocity)),
+                        (MidiEvent::NoteOn, Some(long_press)) => format!(
+                            "{} (once held for {}ms)",
+                            event_list(&long_press.on),
+                            long_press.threshold()
+                        ),
+                        (_, Some(long_press)) => {
+                            if ts.saturating_sub(pressed_at) >= long_press.threshold() * 1000 {
+                                format!("{} (long press)", event_list(&long_press.off))
+                            } else {
+                                let mut tap = mapping.on_sequence(velocity).to_vec();
+                                tap.extend_from_slice(mapping.off_sequence(velocity));
+                                format!("{} (tap)", event_list(&tap))
+                            }
+                        }
+                    });
+                let in_chord = mappings
+                    .chords(msg.channel())
+                    .iter()
+                    .any(|chord| chord.notes().contains(&note));
+                if in_chord {
+                    Some(format!(
+                        "{} (unless played as part of a chord)",
+                        fires.unwrap_or_else(|| "(no mapping)".to_owned())
+                    ))
+                } else {
+                    fires
+                }
+            }
+            MidiEvent::ControlChange { controller, value } => mappings
+                .find_control(controller, msg.channel())
+                .map(|mapping| {
+                    if mapping.is_on(value) {
+                        format!("{} (while on)", event_list(&mapping.on))
+                    } else {
+                        format!("{} (while off)", event_list(&mapping.off))
+                    }
+                }),
+            ref event => Transport::from_event(event)
+                .and_then(|transport| mappings.find_transport(transport))
+                .map(|mapping| event_list(&mapping.on)),
+        };
+        println!(
+            "{} {:>12}us  ch {:>2}  {:<4} vel {:>3}  {}  => {}",
+            device,
+            ts,
+            msg.channel(),
+            note,
+            msg.velocity(),
+            msg.event(),
+            fires.unwrap_or_else(|| "(no mapping)".to_owned())
+        );
+    })?;
+
+    loop {
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Format a sequence of events the way mappings files write them.
+fn event_list(events: &[Event]) -> String {
+    let events: Vec<String> = events.iter().map(|event| event.to_string()).collect();
+    events.join(" ")
+}
+
+/// Build a mappings file interactively: wait for a note to be played, ask
+/// which keys it should press, and add the mapping to `filename`.  Mappings
+/// go in the default layer, ahead of any layer or device sections.
+fn learn(midi_name: Option<&str>, filename: &str) -> Result<(), Box<dyn Error>> {
+    let (sender, receiver) = mpsc::channel();
+    let _connections = connect_devices(midi_name, move |_device, _ts, msg| {
+        // Nothing to do if learning has finished
+        let _ = sender.send(msg);
+    })?;
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        // Forget anything that was played while typing.
+        while receiver.try_recv().is_ok() {}
+
+        println!();
+        println!("Press the piano key to map (or Ctrl-C to finish)");
+        let (note, channel) = loop {
+            let msg = receiver.recv()?;
+            if let (MidiEvent::NoteOn, Some(note)) = (msg.event(), msg.note()) {
+                break (note, msg.channel());
+            }
+        };
+
+        println!(
+            "Enter the keys for {:?} on channel {}, either as a key such as \"a\" or \"Shift\", or as events such as \"KeyDown(a) | KeyUp(a)\".  Leave it empty to skip this note.",
+            note, channel
+        );
+        let input = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+
+        match learned_mapping(note, channel, input) {
+            Ok(mapping) => {
+                let contents = match fs::read_to_string(filename) {
+                    Ok(contents) => contents,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+                    Err(e) => return Err(e.into()),
+                };
+                let mut learned = vec![];
+                mappingfile::write_note_mapping(&mapping, &mut learned)?;
+                let learned = String::from_utf8(learned)?;
+                fs::write(
+                    filename,
+                    mappingfile::add_to_default_layer(&contents, &learned),
+                )?;
+                print!("Added ");
+                mappingfile::write_note_mapping(&mapping, &mut io::stdout())?;
+            }
+            Err(MappingsError::Parse(errors)) => {
+                for error in errors {
+                    println!("{}", error.description);
+                }
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+/// Turn what was typed at the learn prompt into a mapping.  A single key
+/// is pressed along with the note, and released along with it.
+fn learned_mapping(note: MidiNote, channel: u8, input: &str) -> Result<NoteMapping, MappingsError> {
+    let events = match KbdKey::from_name(input) {
+        Some(key) => format!("KeyDown({}) | KeyUp({})", key, key),
+        None => input.to_owned(),
+    };
+    let line = format!("{:?} {}: {}", note, channel, events);
+    let mut mappings = NoteMappings::new();
+    mappingfile::parse(&mut mappings, "input", line.as_bytes())?;
+    Ok(mappings.find(note, channel, None).unwrap())
+}
+
 fn list_devices() -> Result<(), Box<dyn Error>> {
     let mut midi_in = MidiInput::new("perform")?;
     midi_in.ignore(Ignore::None);
@@ -0,0 +1,1331 @@
+//! Reading and writing mappings files.
+//!
+//! Each line is one mapping, written as `<source> <channel>: <on> | <off>`,
+//! where `<on>` and `<off>` are sequences of events such as `KeyDown(Shift)`,
+//! `KeyUp(t)`, `Text("naïve café")`, `MouseDown(Left)`, `MouseMove(10,-5)`,
+//! `Scroll(0,3)`, `Delay(150)`, `NoteMod(Control)`, `NoteMod(None)`,
+//! `Latch(On)`, `Latch(Shift)`, `Latch(Off)`, `SelectLayer(nav)`,
+//! `Transpose(-12)` or `Transpose(Reset)`.  The source is one of:
+//!
+//! * A note such as `Cs4`, or a range of notes such as `C2..B2`, optionally
+//!   with a velocity range (`Cs4@100-127`) to add a velocity layer to the
+//!   preceding mapping for the same notes, or with `Cs4@long` (or
+//!   `Cs4@long=400` for a time in milliseconds) to add sequences for when
+//!   the note is held down rather than tapped.  Similarly `Cs4@double` and
+//!   `Cs4@triple` add sequences for two or three taps in a row, each within
+//!   300 milliseconds of the one before, or e.g. `Cs4@double=200`.  Notes
+//!   can use `*` as the channel to match any channel.
+//! * A controller such as `CC64`, optionally with a threshold (`CC1@100`).
+//! * `PitchBend` or `Pressure`, optionally with a rate (`PitchBend@30`).
+//!   These take keys rather than events: `PitchBend 0: LeftArrow | RightArrow`
+//!   or `Pressure 0: DownArrow`.  They, or a controller, can instead move
+//!   the mouse or scroll with one of `MoveX`, `MoveY`, `ScrollX` or
+//!   `ScrollY`, as in `CC16@600 0: MoveX`.
+//! * `Start`, `Continue` or `Stop`, which have no channel and no off sequence.
+//! * A chord such as `C4+E4+G4`, optionally with the time in milliseconds
+//!   that all of its notes must be pressed within (`C4+E4+G4@80`).
+//!
+//! Notes and controllers can also be given a maximum hold time after the
+//! channel, as in `C4 0 hold=2000: KeyDown(a) | KeyUp(a)`.  Keys they press
+//! are released if they're held for longer than that many milliseconds.
+//!
+//! Lines starting with `#` are comments, and `[name]` or `[name program]`
+//! starts a new layer.  `[device: <name>]` limits the notes after it to the
+//! MIDI device with that port name, until the next `[device: ...]` or layer.
+//! The name can be a pattern such as `[device: Launchkey*]`, where `*`
+//! matches any run of characters and `?` any one character, and
+//! `[device: *]` goes back to matching every device.  The original
+//! `note channel keydown keyup` format is still accepted.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::midi::MidiNote;
+use crate::notemappings::{
+    ChordMapping, ContinuousMapping, ContinuousSource, ControlMapping, Event, KbdKey, Layer,
+    LongPress, MouseAxis, MultiTap, NoteMapping, NoteMappings, Transport, TransportMapping,
+    VelocityLayer, DEFAULT_CHORD_WINDOW_MS, DEFAULT_LONG_PRESS_MS, DEFAULT_TAP_WINDOW_MS,
+};
+
+/// The default number of presses per second for a continuous input at its
+/// extreme.
+const DEFAULT_KEY_RATE: f32 = 20.0;
+
+/// The default number of pixels per second for a continuous input moving
+/// the mouse.
+const DEFAULT_MOVE_RATE: f32 = 400.0;
+
+/// The default number of steps per second for a continuous input scrolling.
+const DEFAULT_SCROLL_RATE: f32 = 10.0;
+
+/// A problem with one line of a mappings file.
+#[derive(Debug)]
+pub struct ParseError {
+    /// The name of the file being read.
+    pub file: String,
+
+    /// The line the problem is on, starting from 1.
+    pub line: usize,
+
+    /// The column the problem starts at, starting from 1.
+    pub column: usize,
+
+    /// What's wrong.
+    pub description: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.description
+        )
+    }
+}
+
+/// Everything that can go wrong when loading a mappings file.
+#[derive(Debug)]
+pub enum MappingsError {
+    /// The file couldn't be read.
+    Io(io::Error),
+
+    /// The file was read, but had problems on one or more lines.
+    Parse(Vec<ParseError>),
+}
+
+impl fmt::Display for MappingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MappingsError::Io(ref e) => write!(f, "unable to read mappings: {}", e),
+            MappingsError::Parse(ref errors) => {
+                for (idx, error) in errors.iter().enumerate() {
+                    if idx != 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for MappingsError {}
+
+impl From<io::Error> for MappingsError {
+    fn from(e: io::Error) -> MappingsError {
+        MappingsError::Io(e)
+    }
+}
+
+/// A problem with a single field: its column and a description.
+type FieldError = (usize, String);
+
+/// A whitespace-separated field, along with the column it starts at.
+#[derive(Clone, Copy)]
+struct Field<'a> {
+    column: usize,
+    txt: &'a str,
+}
+
+impl<'a> Field<'a> {
+    fn error<T>(&self, description: String) -> Result<T, FieldError> {
+        Err((self.column, description))
+    }
+}
+
+/// Split `txt` on whitespace.  `column` is the column that `txt` starts at.
+/// Text in double quotes straight after a `(`, as in `Text("a b")`, is kept
+/// in one field even if it has whitespace in it.
+fn split_fields(txt: &str, column: usize) -> Vec<Field<'_>> {
+    let mut fields = vec![];
+    let mut start = None;
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut prev = None;
+    for (idx, c) in txt.char_indices() {
+        let after_paren = prev == Some('(');
+        prev = Some(c);
+        if quoted {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => (),
+            }
+            continue;
+        }
+        if c == '"' && after_paren {
+            quoted = true;
+        }
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                fields.push(Field {
+                    column: column + txt[..s].chars().count(),
+                    txt: &txt[s..idx],
+                });
+                start = None;
+            }
+            (false, None) => start = Some(idx),
+            _ => (),
+        }
+    }
+    if let Some(s) = start {
+        fields.push(Field {
+            column: column + txt[..s].chars().count(),
+            txt: &txt[s..],
+        });
+    }
+    fields
+}
+
+/// Read mappings from `reader`, adding them to `mappings`.  `file` is only
+/// used to describe where any problems are.  Every line is checked, and all
+/// problems are returned together.
+pub fn parse<R: BufRead>(
+    mappings: &mut NoteMappings,
+    file: &str,
+    reader: R,
+) -> Result<(), MappingsError> {
+    let mut errors = vec![];
+    // The device named by the current `[device: ...]` section, if any
+    let mut device = None;
+    for (idx, line) in reader.lines().enumerate() {
+        let l = line?;
+        if let Err((column, description)) = parse_any_line(mappings, &mut device, &l) {
+            errors.push(ParseError {
+                file: file.to_owned(),
+                line: idx + 1,
+                column,
+                description,
+            });
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(MappingsError::Parse(errors))
+    }
+}
+
+fn parse_any_line(
+    mappings: &mut NoteMappings,
+    device: &mut Option<String>,
+    l: &str,
+) -> Result<(), FieldError> {
+    let fields = split_fields(l, 1);
+    let first = match fields.first() {
+        Some(first) => *first,
+        None => return Ok(()),
+    };
+    if first.txt.starts_with('#') {
+        return Ok(());
+    }
+
+    // Layer sections look like "[nav]", or "[nav 1]" to also select
+    // the layer with Program Change 1.
+    if first.txt.starts_with('[') {
+        let trimmed = l.trim_end();
+        let inner = match trimmed.strip_suffix(']') {
+            Some(inner) => &inner[first.column..],
+            None => return first.error("layer header is missing a closing `]`".to_owned()),
+        };
+
+        // Device sections look like "[device: Launchkey*]".
+        if let Some(name) = inner.trim_start().strip_prefix("device:") {
+            *device = match name.trim() {
+                "" => return first.error("device section has no device name".to_owned()),
+                "*" => None,
+                name => Some(name.to_owned()),
+            };
+            return Ok(());
+        }
+
+        *device = None;
+        let header = split_fields(inner, first.column + 1);
+        let program = match header.as_slice() {
+            [_] => None,
+            [_, program] => Some(parse_number(program, "program", 127)?),
+            [] => return first.error("layer header has no name".to_owned()),
+            [_, _, extra, ..] => return extra.error("unexpected text after program".to_owned()),
+        };
+        #[cfg(feature = "debug")]
+        println!("Got layer: {}", header[0].txt);
+        mappings.add_layer(Layer::new(header[0].txt, program));
+        return Ok(());
+    }
+
+    if is_legacy(&fields) {
+        parse_legacy(mappings, device.as_deref(), &fields)?;
+    } else {
+        parse_line(mappings, device.as_deref(), l)?;
+    }
+    #[cfg(feature = "debug")]
+    println!("Got line: {}", l);
+    Ok(())
+}
+
+/// Current lines have a colon after their source and channel, as in
+/// `C4 0: ...`, `C4 0 : ...` or `Start: ...`.  Legacy lines are
+/// `note channel keydown keyup`, optionally followed by velocity layers
+/// such as `100:T`, and only have a colon in their keys, as in `Cs4 0 : :`
+/// or `C2 9 layer:nav -`.  Shorter lines are legacy unless a field ends in
+/// a colon, so both kinds get an error that makes sense.
+fn is_legacy(fields: &[Field]) -> bool {
+    if fields.iter().take(2).any(|f| f.txt.contains(':')) {
+        return false;
+    }
+    match fields {
+        [_, _, _, keyup, velocities @ ..] => {
+            Event::parse(keyup.txt).is_none() && velocities.iter().all(|f| is_velocity_layer(f.txt))
+        }
+        _ => !fields.iter().any(|f| f.txt.ends_with(':')),
+    }
+}
+
+/// Whether `txt` looks like a legacy velocity layer, `N:c` or `N-M:c`.
+fn is_velocity_layer(txt: &str) -> bool {
+    let (range, key) = match txt.split_once(':') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let is_number = |n: &str| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit());
+    let range_ok = match range.split_once('-') {
+        Some((min, max)) => is_number(min) && is_number(max),
+        None => is_number(range),
+    };
+    range_ok && key.chars().count() == 1
+}
+
+fn parse_number(field: &Field, what: &str, max: u8) -> Result<u8, FieldError> {
+    match field.txt.parse::<u8>() {
+        Ok(n) if n <= max => Ok(n),
+        _ => field.error(format!(
+            "{} `{}` is not a number from 0 to {}",
+            what, field.txt, max
+        )),
+    }
+}
+
+fn parse_channel(field: &Field) -> Result<u8, FieldError> {
+    parse_number(field, "channel", 15)
+}
+
+/// Parse a note name such as "Cs4", rejecting anything with trailing text.
+fn parse_note(field: &Field) -> Result<MidiNote, FieldError> {
+    match MidiNote::new_from_text(field.txt) {
+        Ok(note) if format!("{:?}", note).eq_ignore_ascii_case(field.txt) => Ok(note),
+        _ => field.error(format!("`{}` is not a note name such as Cs4", field.txt)),
+    }
+}
+
+fn is_note_range(txt: &str) -> bool {
+    txt.contains("..")
+}
+
+/// Parse a note such as "Cs4", or a range of notes such as "C2..B2".
+/// Returns the lowest and highest notes.
+fn parse_note_range(field: &Field) -> Result<(MidiNote, MidiNote), FieldError> {
+    let dots = match field.txt.find("..") {
+        Some(dots) => dots,
+        None => {
+            let note = parse_note(field)?;
+            return Ok((note, note));
+        }
+    };
+    let first = parse_note(&Field {
+        column: field.column,
+        txt: &field.txt[..dots],
+    })?;
+    let last = parse_note(&Field {
+        column: field.column + field.txt[..dots + 2].chars().count(),
+        txt: &field.txt[dots + 2..],
+    })?;
+    if (first as u8) > (last as u8) {
+        return field.error(format!("note range `{}` is backwards", field.txt));
+    }
+    Ok((first, last))
+}
+
+/// Split a source such as "CC1@100" into its name and qualifier fields.
+fn split_qualifier<'a>(field: &Field<'a>) -> (Field<'a>, Option<Field<'a>>) {
+    match field.txt.find('@') {
+        Some(at) => (
+            Field {
+                column: field.column,
+                txt: &field.txt[..at],
+            },
+            Some(Field {
+                column: field.column + field.txt[..=at].chars().count(),
+                txt: &field.txt[at + 1..],
+            }),
+        ),
+        None => (*field, None),
+    }
+}
+
+fn parse_transport(txt: &str) -> Option<Transport> {
+    match txt.to_lowercase().as_str() {
+        "start" => Some(Transport::Start),
+        "continue" => Some(Transport::Continue),
+        "stop" => Some(Transport::Stop),
+        _ => None,
+    }
+}
+
+fn parse_continuous(txt: &str) -> Option<ContinuousSource> {
+    match txt.to_lowercase().as_str() {
+        "pitchbend" => Some(ContinuousSource::PitchBend),
+        "pressure" => Some(ContinuousSource::ChannelPressure),
+        _ => None,
+    }
+}
+
+/// The controller number of a source such as "CC64", if it is one.
+fn parse_controller(field: &Field) -> Option<Result<u8, FieldError>> {
+    if field.txt.len() < 2 || !field.txt[..2].eq_ignore_ascii_case("cc") {
+        return None;
+    }
+    let number = Field {
+        column: field.column + 2,
+        txt: &field.txt[2..],
+    };
+    Some(parse_number(&number, "controller", 127))
+}
+
+fn parse_rate(field: Option<Field>, default: f32) -> Result<f32, FieldError> {
+    match field {
+        Some(r) => match r.txt.parse::<f32>() {
+            Ok(rate) if rate > 0.0 => Ok(rate),
+            _ => r.error(format!("rate `{}` is not a positive number", r.txt)),
+        },
+        None => Ok(default),
+    }
+}
+
+/// The mouse axis a continuous input drives, if its body is just one such
+/// as `MoveX` or `ScrollY`.
+fn parse_mouse_axis(on: &[Field], off: &[Field]) -> Option<MouseAxis> {
+    match (on, off) {
+        ([axis], []) => MouseAxis::from_name(axis.txt),
+        _ => None,
+    }
+}
+
+fn default_rate(mouse: Option<MouseAxis>) -> f32 {
+    match mouse {
+        None => DEFAULT_KEY_RATE,
+        Some(MouseAxis::MoveX) | Some(MouseAxis::MoveY) => DEFAULT_MOVE_RATE,
+        Some(MouseAxis::ScrollX) | Some(MouseAxis::ScrollY) => DEFAULT_SCROLL_RATE,
+    }
+}
+
+fn parse_threshold(field: Option<Field>) -> Result<u8, FieldError> {
+    match field {
+        Some(t) => parse_number(&t, "threshold", 127),
+        None => Ok(64),
+    }
+}
+
+fn parse_key(field: &Field) -> Result<KbdKey, FieldError> {
+    match KbdKey::from_name(field.txt) {
+        Some(key) => Ok(key),
+        None => field.error(format!("`{}` is not a key name", field.txt)),
+    }
+}
+
+/// Only note mappings know which device they're for.
+fn check_any_device(source: &Field, device: Option<&str>) -> Result<(), FieldError> {
+    match device {
+        Some(device) => source.error(format!(
+            "only notes can be limited to a device, but `{}` is in the section for `{}`",
+            source.txt, device
+        )),
+        None => Ok(()),
+    }
+}
+
+fn parse_line(
+    mappings: &mut NoteMappings,
+    device: Option<&str>,
+    line: &str,
+) -> Result<(), FieldError> {
+    // `is_legacy()` already checked that there's a colon.
+    let colon = line.find(':').unwrap();
+    let head = split_fields(&line[..colon], 1);
+    let body_column = line[..=colon].chars().count() + 1;
+    let body = split_fields(&line[colon + 1..], body_column);
+    let (on_txt, off_txt) = match body.iter().position(|field| field.txt == "|") {
+        Some(idx) => (&body[..idx], &body[idx + 1..]),
+        None => (&body[..], &body[body.len()..]),
+    };
+
+    let (source, qualifier) = split_qualifier(&head[0]);
+
+    if let Some(transport) = parse_transport(source.txt) {
+        check_any_device(&source, device)?;
+        if let Some(extra) = head.get(1) {
+            return extra.error("transport messages have no channel".to_owned());
+        }
+        if let Some(q) = qualifier {
+            return q.error("transport messages take no `@` option".to_owned());
+        }
+        if let Some(off) = off_txt.first() {
+            return off.error("transport messages have no off sequence".to_owned());
+        }
+        let mut mapping = TransportMapping::new(transport);
+        mapping.on = parse_events(on_txt)?;
+        mappings.add_transport(mapping);
+        return Ok(());
+    }
+
+    let channel_field = match head.get(1) {
+        Some(channel) => channel,
+        None => return source.error(format!("`{}` needs a channel", source.txt)),
+    };
+    // Notes can use "*" for any channel, but nothing else can.
+    let any_channel = if channel_field.txt == "*" {
+        None
+    } else {
+        Some(parse_channel(channel_field)?)
+    };
+    let channel = match any_channel {
+        Some(channel) => channel,
+        None if is_note_range(source.txt) || parse_note(&source).is_ok() => 0,
+        None => return channel_field.error("only notes can use `*` for any channel".to_owned()),
+    };
+    let max_hold = match head.get(2) {
+        Some(hold) => Some(parse_hold(hold)?),
+        None => None,
+    };
+    if let Some(extra) = head.get(3) {
+        return extra.error("expected `:` after the hold time".to_owned());
+    }
+
+    // A controller that moves the mouse is a continuous input, rather than
+    // one that runs sequences.
+    let mouse = parse_mouse_axis(on_txt, off_txt);
+    let continuous = match (parse_continuous(source.txt), mouse) {
+        (Some(continuous), _) => Some(continuous),
+        (None, Some(_)) => match parse_controller(&source) {
+            Some(controller) => Some(ContinuousSource::Control(controller?)),
+            None => None,
+        },
+        (None, None) => None,
+    };
+
+    if let Some(continuous) = continuous {
+        check_any_device(&source, device)?;
+        if let Some(hold) = head.get(2) {
+            return hold.error("continuous inputs take no hold time".to_owned());
+        }
+        let rate = parse_rate(qualifier, default_rate(mouse))?;
+        let mut mapping = ContinuousMapping::new(continuous, channel, rate);
+        match (continuous, mouse) {
+            (_, Some(axis)) => mapping.mouse = Some(axis),
+            (ContinuousSource::PitchBend, None) => {
+                mapping.negative = parse_optional_key(on_txt)?;
+                mapping.positive = parse_optional_key(off_txt)?;
+            }
+            (ContinuousSource::ChannelPressure, None) | (ContinuousSource::Control(_), None) => {
+                if let Some(off) = off_txt.first() {
+                    return off.error("pressure only takes one key".to_owned());
+                }
+                mapping.positive = parse_optional_key(on_txt)?;
+            }
+        }
+        mappings.add_continuous(mapping);
+        return Ok(());
+    }
+
+    if let Some(controller) = parse_controller(&source) {
+        check_any_device(&source, device)?;
+        let mut mapping = ControlMapping::new(controller?, channel, parse_threshold(qualifier)?);
+        mapping.on = parse_events(on_txt)?;
+        mapping.off = parse_events(off_txt)?;
+        mapping.max_hold = max_hold;
+        mappings.add_control(mapping);
+        return Ok(());
+    }
+
+    if source.txt.contains('+') {
+        check_any_device(&source, device)?;
+        if let Some(hold) = head.get(2) {
+            return hold.error("chords take no hold time".to_owned());
+        }
+        let mut notes = vec![];
+        let mut column = source.column;
+        for note_txt in source.txt.split('+') {
+            notes.push(parse_note(&Field {
+                column,
+                txt: note_txt,
+            })?);
+            column += note_txt.chars().count() + 1;
+        }
+        let window = match qualifier {
+            Some(w) => match w.txt.parse::<u64>() {
+                Ok(window) if window > 0 => window,
+                _ => {
+                    return w.error(format!(
+                        "chord window `{}` is not a positive number of milliseconds",
+                        w.txt
+                    ))
+                }
+            },
+            None => DEFAULT_CHORD_WINDOW_MS,
+        };
+        let mut mapping = ChordMapping::new(&notes, channel, window);
+        if mapping.notes().len() < 2 {
+            return source.error("a chord needs at least two different notes".to_owned());
+        }
+        mapping.on = parse_events(on_txt)?;
+        mapping.off = parse_events(off_txt)?;
+        mappings.add_chord(mapping);
+        return Ok(());
+    }
+
+    let (note, last_note) = parse_note_range(&source)?;
+    let on = parse_events(on_txt)?;
+    let off = parse_events(off_txt)?;
+    match qualifier {
+        // A long press belongs to the mapping for the same note
+        Some(long) if is_long_press(long.txt) => {
+            if let Some(hold) = head.get(2) {
+                return hold
+                    .error("long presses use the hold time of the note's main mapping".to_owned());
+            }
+            let mut long_press = LongPress::new(parse_long_press(&long)?);
+            long_press.on = on;
+            long_press.off = off;
+            let mapping = main_mapping(mappings, note, last_note, any_channel, device);
+            if mapping.long_press.is_some() {
+                return source.error(format!("`{}` already has a long press", source.txt));
+            }
+            mapping.long_press = Some(long_press);
+        }
+        // So do multiple taps
+        Some(taps) if tap_count(taps.txt).is_some() => {
+            if let Some(hold) = head.get(2) {
+                return hold.error(
+                    "multiple taps use the hold time of the note's main mapping".to_owned(),
+                );
+            }
+            let (count, window) = parse_multi_tap(&taps)?;
+            let mut multi_tap = MultiTap::new(count, window);
+            multi_tap.on = on;
+            multi_tap.off = off;
+            let mapping = main_mapping(mappings, note, last_note, any_channel, device);
+            if mapping.taps.iter().any(|tap| tap.count() == count) {
+                return source.error(format!(
+                    "`{}` already has a {} tap",
+                    source.txt,
+                    TAP_NAMES[usize::from(count) - 2]
+                ));
+            }
+            mapping.taps.push(multi_tap);
+        }
+        Some(other) if !other.txt.starts_with(|c: char| c.is_ascii_digit()) => {
+            return other.error(format!(
+                "unknown qualifier `{}` (expected a velocity range, `long`, `double` or `triple`)",
+                other.txt
+            ));
+        }
+        // And so does a velocity layer
+        Some(range) => {
+            if let Some(hold) = head.get(2) {
+                return hold.error(
+                    "velocity layers use the hold time of the note's main mapping".to_owned(),
+                );
+            }
+            let (min, max) = parse_range(&range)?;
+            let mut layer = VelocityLayer::new(min, max);
+            layer.on = on;
+            layer.off = off;
+            main_mapping(mappings, note, last_note, any_channel, device)
+                .velocity_layers
+                .push(layer);
+        }
+        None => {
+            let mut mapping =
+                NoteMapping::with_range(note, last_note, any_channel, device.map(str::to_owned));
+            mapping.on = on;
+            mapping.off = off;
+            mapping.max_hold = max_hold;
+            mappings.add(mapping);
+        }
+    }
+    Ok(())
+}
+
+/// The mapping in the current layer for exactly these notes, channel and
+/// device, which is added if there isn't one yet.
+fn main_mapping<'a>(
+    mappings: &'a mut NoteMappings,
+    note: MidiNote,
+    last_note: MidiNote,
+    channel: Option<u8>,
+    device: Option<&str>,
+) -> &'a mut NoteMapping {
+    if mappings
+        .last_mapping_mut(note, last_note, channel, device)
+        .is_none()
+    {
+        mappings.add(NoteMapping::with_range(
+            note,
+            last_note,
+            channel,
+            device.map(str::to_owned),
+        ));
+    }
+    mappings
+        .last_mapping_mut(note, last_note, channel, device)
+        .unwrap()
+}
+
+/// The names of multiple taps, starting from two taps.
+const TAP_NAMES: [&str; 2] = ["double", "triple"];
+
+/// The number of taps named by a qualifier such as "double" or
+/// "triple=400", if it names any.
+fn tap_count(txt: &str) -> Option<u8> {
+    let name = txt.split('=').next().unwrap();
+    TAP_NAMES
+        .iter()
+        .position(|tap_name| *tap_name == name)
+        .map(|idx| idx as u8 + 2)
+}
+
+/// Parse multiple taps such as "double", or "triple=400" with a window in
+/// milliseconds.  Returns the number of taps and the window.
+fn parse_multi_tap(field: &Field) -> Result<(u8, u64), FieldError> {
+    let count = tap_count(field.txt).unwrap();
+    let window = match field.txt.find('=') {
+        Some(eq) => match field.txt[eq + 1..].parse::<u64>() {
+            Ok(ms) if ms > 0 => ms,
+            _ => {
+                return field.error(format!(
+                    "tap window `{}` is not a positive number of milliseconds",
+                    &field.txt[eq + 1..]
+                ))
+            }
+        },
+        None => DEFAULT_TAP_WINDOW_MS,
+    };
+    Ok((count, window))
+}
+
+fn is_long_press(txt: &str) -> bool {
+    txt == "long" || txt.starts_with("long=")
+}
+
+/// Parse a long press threshold such as "long=300", in milliseconds, or
+/// "long" for the default.
+fn parse_long_press(field: &Field) -> Result<u64, FieldError> {
+    match field.txt.strip_prefix("long=") {
+        Some(ms) => match ms.parse::<u64>() {
+            Ok(ms) if ms > 0 => Ok(ms),
+            _ => field.error(format!(
+                "long press time `{}` is not a positive number of milliseconds",
+                ms
+            )),
+        },
+        None => Ok(DEFAULT_LONG_PRESS_MS),
+    }
+}
+
+/// Parse a maximum hold time such as "hold=2000", in milliseconds.
+fn parse_hold(field: &Field) -> Result<u64, FieldError> {
+    match field.txt.strip_prefix("hold=") {
+        Some(ms) => match ms.parse::<u64>() {
+            Ok(ms) if ms > 0 => Ok(ms),
+            _ => field.error(format!(
+                "hold time `{}` is not a positive number of milliseconds",
+                ms
+            )),
+        },
+        None => field.error(format!(
+            "expected `:` or a hold time such as hold=2000, found `{}`",
+            field.txt
+        )),
+    }
+}
+
+fn parse_events(fields: &[Field]) -> Result<Vec<Event>, FieldError> {
+    fields
+        .iter()
+        .map(|field| match Event::parse(field.txt) {
+            Some(event) => Ok(event),
+            None => field.error(format!(
+                "`{}` is not an event such as KeyDown(a), KeyUp(a), Text(\"a\"), Delay(10), NoteMod(Shift) or SelectLayer(name)",
+                field.txt
+            )),
+        })
+        .collect()
+}
+
+/// Parse zero or one key.
+fn parse_optional_key(fields: &[Field]) -> Result<Option<KbdKey>, FieldError> {
+    match fields {
+        [] => Ok(None),
+        [key] => parse_key(key).map(Some),
+        [_, extra, ..] => extra.error("expected only one key".to_owned()),
+    }
+}
+
+/// Parse a velocity range such as "100-127", or "100" for "100-127".
+fn parse_range(field: &Field) -> Result<(u8, u8), FieldError> {
+    let mut range_fields = field.txt.splitn(2, '-');
+    let min_txt = range_fields.next().unwrap();
+    let min = parse_number(
+        &Field {
+            column: field.column,
+            txt: min_txt,
+        },
+        "velocity",
+        127,
+    )?;
+    let max = match range_fields.next() {
+        Some(m) => parse_number(
+            &Field {
+                column: field.column + min_txt.chars().count() + 1,
+                txt: m,
+            },
+            "velocity",
+            127,
+        )?,
+        None => 127,
+    };
+    if min > max {
+        return field.error(format!("velocity range `{}` is backwards", field.txt));
+    }
+    Ok((min, max))
+}
+
+/// Parse a single-character key from the legacy format.
+fn parse_legacy_char(field: &Field) -> Result<char, FieldError> {
+    let mut chars = field.txt.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => field.error(format!("key `{}` is not a single character", field.txt)),
+    }
+}
+
+fn parse_legacy(
+    mappings: &mut NoteMappings,
+    device: Option<&str>,
+    fields: &[Field],
+) -> Result<(), FieldError> {
+    if fields.len() < 4 {
+        let last = fields.last().unwrap();
+        return Err((
+            last.column + last.txt.chars().count(),
+            format!(
+                "expected 4 fields (note channel keydown keyup), found {}",
+                fields.len()
+            ),
+        ));
+    }
+    let note_txt = &fields[0];
+    let channel_txt = &fields[1];
+    let keydown_txt = &fields[2];
+    let keyup_txt = &fields[3];
+
+    // Transport lines look like "Start 0 Space Space", which taps
+    // Space when the transport starts.  The channel is ignored.
+    if let Some(transport) = parse_transport(note_txt.txt) {
+        check_any_device(note_txt, device)?;
+        let mut mapping = TransportMapping::new(transport);
+        mapping.on = vec![
+            Event::KeyDown(parse_key(keydown_txt)?),
+            Event::KeyUp(parse_key(keyup_txt)?),
+        ];
+        mappings.add_transport(mapping);
+        return Ok(());
+    }
+
+    let channel = parse_channel(channel_txt)?;
+    let (source, qualifier) = split_qualifier(note_txt);
+
+    // Controller lines look like "CC64 0 Shift Shift", with an
+    // optional threshold such as "CC1@100".
+    if let Some(controller) = parse_controller(&source) {
+        check_any_device(&source, device)?;
+        let mut mapping = ControlMapping::new(controller?, channel, parse_threshold(qualifier)?);
+        mapping.on = vec![Event::KeyDown(parse_key(keydown_txt)?)];
+        mapping.off = vec![Event::KeyUp(parse_key(keyup_txt)?)];
+        mappings.add_control(mapping);
+        return Ok(());
+    }
+
+    // Continuous lines look like "PitchBend 0 LeftArrow RightArrow",
+    // with an optional rate such as "PitchBend@30".  Pressure only
+    // uses the first key.
+    if let Some(continuous) = parse_continuous(source.txt) {
+        check_any_device(&source, device)?;
+        let mut mapping = ContinuousMapping::new(
+            continuous,
+            channel,
+            parse_rate(qualifier, DEFAULT_KEY_RATE)?,
+        );
+        match continuous {
+            ContinuousSource::PitchBend => {
+                mapping.negative = Some(parse_key(keydown_txt)?);
+                mapping.positive = Some(parse_key(keyup_txt)?);
+            }
+            ContinuousSource::ChannelPressure | ContinuousSource::Control(_) => {
+                mapping.positive = Some(parse_key(keydown_txt)?);
+            }
+        }
+        mappings.add_continuous(mapping);
+        return Ok(());
+    }
+
+    if let Some(q) = qualifier {
+        return q.error("notes take no `@` option in this format".to_owned());
+    }
+    let note = parse_note(&source)?;
+
+    // A note can switch layers with e.g. "C2 9 layer:nav -"
+    if let Some(layer_name) = keydown_txt.txt.strip_prefix("layer:") {
+        let mut mapping = NoteMapping::new(note, channel, device.map(str::to_owned));
+        mapping.on = vec![Event::SelectLayer(layer_name.to_owned())];
+        mappings.add(mapping);
+        return Ok(());
+    }
+
+    let keydown = parse_legacy_char(keydown_txt)?;
+    let keyup = parse_legacy_char(keyup_txt)?;
+
+    let mut mapping = NoteMapping::new(note, channel, device.map(str::to_owned));
+    mapping.on = NoteMapping::down_event(keydown, None, None);
+    mapping.off = NoteMapping::up_event(keyup, None, None);
+
+    // Any further fields are velocity layers, such as "100:T" to type
+    // T when struck with a velocity of 100 or more, or "1-30:." for
+    // a range.
+    for velocity_txt in &fields[4..] {
+        let colon = match velocity_txt.txt.find(':') {
+            Some(colon) => colon,
+            None => {
+                return velocity_txt.error(format!(
+                    "velocity layer `{}` should look like 100:T",
+                    velocity_txt.txt
+                ))
+            }
+        };
+        let (min, max) = parse_range(&Field {
+            column: velocity_txt.column,
+            txt: &velocity_txt.txt[..colon],
+        })?;
+        let key = parse_legacy_char(&Field {
+            column: velocity_txt.column + velocity_txt.txt[..=colon].chars().count(),
+            txt: &velocity_txt.txt[colon + 1..],
+        })?;
+        let mut layer = VelocityLayer::new(min, max);
+        layer.on = NoteMapping::down_event(key, None, None);
+        layer.off = NoteMapping::up_event(key, None, None);
+        mapping.velocity_layers.push(layer);
+    }
+
+    mappings.add(mapping);
+    Ok(())
+}
+
+/// Write `mappings` in the format that `parse()` reads.
+pub fn write<W: Write>(mappings: &NoteMappings, out: &mut W) -> io::Result<()> {
+    for (idx, layer) in mappings.layers().iter().enumerate() {
+        // The first layer is the default one, which needs no header.
+        if idx != 0 {
+            writeln!(out)?;
+            match layer.program() {
+                Some(program) => writeln!(out, "[{} {}]", layer.name(), program)?,
+                None => writeln!(out, "[{}]", layer.name())?,
+            }
+        }
+
+        // Mappings for particular devices go in sections at the end of the
+        // layer, since everything else has to be outside of them.
+        let mut devices: Vec<&str> = vec![];
+        for mapping in layer.mappings() {
+            match mapping.instrument_name() {
+                Some(device) => {
+                    if !devices.contains(&device) {
+                        devices.push(device);
+                    }
+                }
+                None => write_note_mapping(mapping, out)?,
+            }
+        }
+
+        for mapping in layer.controls() {
+            writeln!(
+                out,
+                "CC{}@{} {}{}:{}",
+                mapping.controller(),
+                mapping.threshold(),
+                mapping.channel(),
+                hold(mapping.max_hold),
+                sequences(&mapping.on, &mapping.off)
+            )?;
+        }
+
+        for mapping in layer.continuous() {
+            let key_txt = |key: &Option<KbdKey>| match key {
+                Some(key) => format!(" {}", key),
+                None => String::new(),
+            };
+            let source = match mapping.source() {
+                ContinuousSource::PitchBend => "PitchBend".to_owned(),
+                ContinuousSource::ChannelPressure => "Pressure".to_owned(),
+                ContinuousSource::Control(controller) => format!("CC{}", controller),
+            };
+            match (mapping.source(), mapping.mouse) {
+                (_, Some(axis)) => writeln!(
+                    out,
+                    "{}@{} {}: {}",
+                    source,
+                    mapping.rate(),
+                    mapping.channel(),
+                    axis
+                )?,
+                (ContinuousSource::PitchBend, None) => writeln!(
+                    out,
+                    "{}@{} {}:{} |{}",
+                    source,
+                    mapping.rate(),
+                    mapping.channel(),
+                    key_txt(&mapping.negative),
+                    key_txt(&mapping.positive)
+                )?,
+                (_, None) => writeln!(
+                    out,
+                    "{}@{} {}:{}",
+                    source,
+                    mapping.rate(),
+                    mapping.channel(),
+                    key_txt(&mapping.positive)
+                )?,
+            }
+        }
+
+        for mapping in layer.chords() {
+            let notes: Vec<String> = mapping
+                .notes()
+                .iter()
+                .map(|note| format!("{:?}", note))
+                .collect();
+            writeln!(
+                out,
+                "{}@{} {}:{}",
+                notes.join("+"),
+                mapping.window(),
+                mapping.channel(),
+                sequences(&mapping.on, &mapping.off)
+            )?;
+        }
+
+        for mapping in layer.transport() {
+            writeln!(
+                out,
+                "{:?}:{}",
+                mapping.transport(),
+                sequences(&mapping.on, &[])
+            )?;
+        }
+
+        for device in devices {
+            writeln!(out)?;
+            writeln!(out, "[device: {}]", device)?;
+            for mapping in layer.mappings() {
+                if mapping.instrument_name() == Some(device) {
+                    write_note_mapping(mapping, out)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add `lines` to the mappings file `contents`, in the default layer and
+/// outside of any device section.  That's everything before the first
+/// `[...]` header, so they go just above it, ahead of any blank lines and
+/// comments leading up to it, or at the end if there are no headers.
+pub fn add_to_default_layer(contents: &str, lines: &str) -> String {
+    let existing: Vec<&str> = contents.lines().collect();
+    let mut at = existing.len();
+    if let Some(header) = existing
+        .iter()
+        .position(|line| line.trim_start().starts_with('['))
+    {
+        at = header;
+        while at > 0 {
+            let line = existing[at - 1].trim_start();
+            if !line.is_empty() && !line.starts_with('#') {
+                break;
+            }
+            at -= 1;
+        }
+    }
+
+    let mut out = String::new();
+    for line in &existing[..at] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str(lines);
+    for line in &existing[at..] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Write a single note mapping, along with its velocity layers, in the
+/// format that `parse()` reads.
+pub fn write_note_mapping<W: Write>(mapping: &NoteMapping, out: &mut W) -> io::Result<()> {
+    let notes = if mapping.note() == mapping.last_note() {
+        format!("{:?}", mapping.note())
+    } else {
+        format!("{:?}..{:?}", mapping.note(), mapping.last_note())
+    };
+    let channel = match mapping.channel() {
+        Some(channel) => channel.to_string(),
+        None => "*".to_owned(),
+    };
+    writeln!(
+        out,
+        "{} {}{}:{}",
+        notes,
+        channel,
+        hold(mapping.max_hold),
+        sequences(&mapping.on, &mapping.off)
+    )?;
+    for velocity_layer in &mapping.velocity_layers {
+        writeln!(
+            out,
+            "{}@{}-{} {}:{}",
+            notes,
+            velocity_layer.min(),
+            velocity_layer.max(),
+            channel,
+            sequences(&velocity_layer.on, &velocity_layer.off)
+        )?;
+    }
+    if let Some(ref long_press) = mapping.long_press {
+        writeln!(
+            out,
+            "{}@long={} {}:{}",
+            notes,
+            long_press.threshold(),
+            channel,
+            sequences(&long_press.on, &long_press.off)
+        )?;
+    }
+    for tap in &mapping.taps {
+        writeln!(
+            out,
+            "{}@{}={} {}:{}",
+            notes,
+            TAP_NAMES[usize::from(tap.count()) - 2],
+            tap.window(),
+            channel,
+            sequences(&tap.on, &tap.off)
+        )?;
+    }
+    Ok(())
+}
+
+/// Format a maximum hold time as " hold=<ms>", if there is one.
+fn hold(max_hold: Option<u64>) -> String {
+    match max_hold {
+        Some(ms) => format!(" hold={}", ms),
+        None => String::new(),
+    }
+}
+
+/// Format an on and off sequence as " <on> | <off>".
+fn sequences(on: &[Event], off: &[Event]) -> String {
+    let mut s = String::new();
+    for event in on {
+        s.push_str(&format!(" {}", event));
+    }
+    if !off.is_empty() {
+        s.push_str(" |");
+        for event in off {
+            s.push_str(&format!(" {}", event));
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(src: &str) -> Result<NoteMappings, MappingsError> {
+        let mut mappings = NoteMappings::new();
+        parse(&mut mappings, "test.txt", src.as_bytes())?;
+        Ok(mappings)
+    }
+
+    fn on_events(mappings: &NoteMappings, note: MidiNote) -> Vec<Event> {
+        mappings
+            .find(note, 0, None)
+            .unwrap()
+            .on_sequence(64)
+            .to_vec()
+    }
+
+    #[test]
+    fn current_format() {
+        let a = KbdKey::Layout('a');
+        for src in &[
+            "C4 0: KeyDown(a) | KeyUp(a)",
+            "C4 0:KeyDown(a) | KeyUp(a)",
+            "C4 0 : KeyDown(a) | KeyUp(a)",
+        ] {
+            let mappings = parse_str(src).unwrap();
+            assert_eq!(
+                on_events(&mappings, MidiNote::C4),
+                vec![Event::KeyDown(a.clone())]
+            );
+            let off = mappings.find(MidiNote::C4, 0, None).unwrap();
+            assert_eq!(off.off_sequence(64), &[Event::KeyUp(a.clone())][..]);
+        }
+
+        for src in &[
+            "Start: KeyDown(a)",
+            "Start:KeyDown(a)",
+            "Start : KeyDown(a)",
+        ] {
+            let mappings = parse_str(src).unwrap();
+            let start = mappings.find_transport(Transport::Start).unwrap();
+            assert_eq!(start.on, vec![Event::KeyDown(a.clone())]);
+        }
+    }
+
+    #[test]
+    fn legacy_format() {
+        let mappings = parse_str(
+            "C4 0 a a\nD4 0 : :\nE4 0 ( |\nCC64 0 Shift Shift\nF4 0 t t 100:T 1-30::\nC2 9 layer:nav -",
+        )
+        .unwrap();
+        assert!(on_events(&mappings, MidiNote::C4).contains(&Event::KeyDown(KbdKey::Layout('a'))));
+        assert!(on_events(&mappings, MidiNote::D4).contains(&Event::KeyDown(KbdKey::Layout(':'))));
+        assert!(on_events(&mappings, MidiNote::E4).contains(&Event::KeyDown(KbdKey::Layout('('))));
+        let pedal = mappings.find_control(64, 0).unwrap();
+        assert_eq!(pedal.on, vec![Event::KeyDown(KbdKey::Shift)]);
+        let layers = &mappings
+            .find(MidiNote::F4, 0, None)
+            .unwrap()
+            .velocity_layers;
+        assert_eq!(layers.len(), 2);
+        assert!(layers[0].on.contains(&Event::KeyDown(KbdKey::Layout('T'))));
+        assert!(layers[1].on.contains(&Event::KeyDown(KbdKey::Layout(':'))));
+        let switch = mappings.find(MidiNote::C2, 9, None).unwrap();
+        assert_eq!(switch.on, vec![Event::SelectLayer("nav".to_owned())]);
+    }
+
+    #[test]
+    fn legacy_and_current_lines_mixed() {
+        let mappings = parse_str("C4 0 a a\nD4 0: KeyDown(b) | KeyUp(b)").unwrap();
+        assert!(on_events(&mappings, MidiNote::C4).contains(&Event::KeyDown(KbdKey::Layout('a'))));
+        assert_eq!(
+            on_events(&mappings, MidiNote::D4),
+            vec![Event::KeyDown(KbdKey::Layout('b'))]
+        );
+    }
+
+    /// Every problem in `src`, as printed by `--check`.
+    fn errors(src: &str) -> Vec<String> {
+        match parse_str(src) {
+            Err(MappingsError::Parse(errors)) => errors.iter().map(|e| e.to_string()).collect(),
+            Err(e) => panic!("{}", e),
+            Ok(_) => vec![],
+        }
+    }
+
+    #[test]
+    fn error_locations() {
+        let src = "Xx4 0 a a\n\
+                   C4 a b c\n\
+                   \n\
+                   C4 0 ab b\n\
+                   C4 0: Text(\"\u{e9}\") KeyDown(Foo) | KeyUp(a)\n\
+                   C4@9-1 0: \n\
+                   C4@1-x 0:\n\
+                   C4@quadruple 0: KeyDown(a)\n\
+                   CC200 0: \n\
+                   C4+E4+Q4 0: \n\
+                   \x20 C4 16: \n\
+                   C4 0 hold=x: \n\
+                   C4 0";
+        assert_eq!(
+            errors(src),
+            vec![
+                "test.txt:1:1: `Xx4` is not a note name such as Cs4",
+                "test.txt:2:4: channel `a` is not a number from 0 to 15",
+                "test.txt:4:6: key `ab` is not a single character",
+                "test.txt:5:17: `KeyDown(Foo)` is not an event such as KeyDown(a), KeyUp(a), \
+                 Text(\"a\"), Delay(10), NoteMod(Shift) or SelectLayer(name)",
+                "test.txt:6:4: velocity range `9-1` is backwards",
+                "test.txt:7:6: velocity `x` is not a number from 0 to 127",
+                "test.txt:8:4: unknown qualifier `quadruple` (expected a velocity range, \
+                 `long`, `double` or `triple`)",
+                "test.txt:9:3: controller `200` is not a number from 0 to 127",
+                "test.txt:10:7: `Q4` is not a note name such as Cs4",
+                "test.txt:11:6: channel `16` is not a number from 0 to 15",
+                "test.txt:12:6: hold time `x` is not a positive number of milliseconds",
+                "test.txt:13:5: expected 4 fields (note channel keydown keyup), found 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn add_to_default_layer_before_sections() {
+        let learned = "C4 0: KeyDown(a) | KeyUp(a)\n";
+        assert_eq!(add_to_default_layer("", learned), learned);
+        assert_eq!(
+            add_to_default_layer("D4 0: KeyDown(b)\n\n", learned),
+            "D4 0: KeyDown(b)\n\nC4 0: KeyDown(a) | KeyUp(a)\n"
+        );
+
+        let contents = "D4 0: KeyDown(b)\n\n# Navigation\n[nav 1]\nC2 0: KeyDown(Home)\n\n[device: Pads]\nC1 0: KeyDown(c)\n";
+        let updated = add_to_default_layer(contents, learned);
+        assert_eq!(
+            updated,
+            "D4 0: KeyDown(b)\nC4 0: KeyDown(a) | KeyUp(a)\n\n# Navigation\n[nav 1]\nC2 0: KeyDown(Home)\n\n[device: Pads]\nC1 0: KeyDown(c)\n"
+        );
+
+        let mappings = parse_str(&updated).unwrap();
+        let mapping = mappings.find(MidiNote::C4, 0, Some("Pads")).unwrap();
+        assert_eq!(mapping.instrument_name(), None);
+        assert_eq!(mapping.on, vec![Event::KeyDown(KbdKey::Layout('a'))]);
+    }
+
+    #[test]
+    fn written_keys_read_back() {
+        let chars: Vec<char> = ('!'..='~')
+            .chain(" \t\u{7f}\u{e9}\u{2192}".chars())
+            .collect();
+        let mut mappings = NoteMappings::new();
+        for (idx, &c) in chars.iter().enumerate() {
+            let mut mapping = NoteMapping::new(MidiNote::new(idx as u8).unwrap(), 0, None);
+            mapping.on = vec![Event::KeyDown(KbdKey::Layout(c))];
+            mapping.off = vec![Event::KeyUp(KbdKey::Layout(c))];
+            mappings.add(mapping);
+        }
+        let mut written = vec![];
+        write(&mappings, &mut written).unwrap();
+        let read = parse_str(&String::from_utf8(written).unwrap()).unwrap();
+        for (idx, &c) in chars.iter().enumerate() {
+            let mapping = read
+                .find(MidiNote::new(idx as u8).unwrap(), 0, None)
+                .unwrap();
+            assert_eq!(mapping.on, vec![Event::KeyDown(KbdKey::Layout(c))]);
+            assert_eq!(mapping.off, vec![Event::KeyUp(KbdKey::Layout(c))]);
+        }
+    }
+}
@@ -1,10 +1,91 @@
-#[derive(Debug, PartialEq)]
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum MidiEvent {
     NoteOn,
     NoteOff,
+
+    /// A controller (pedal, wheel, knob) moved to a new value
+    ControlChange {
+        controller: u8,
+        value: u8,
+    },
+
+    /// The pitch wheel moved.  `value` is 14 bits, centered on 8192.
+    PitchBend {
+        value: u16,
+    },
+
+    /// Channel-wide aftertouch
+    ChannelPressure {
+        pressure: u8,
+    },
+
+    /// A patch button was pressed
+    ProgramChange {
+        program: u8,
+    },
+
+    /// A System Exclusive message, including the leading 0xF0 and
+    /// trailing 0xF7 bytes
+    SysEx(Vec<u8>),
+
+    /// A MIDI Time Code quarter frame
+    TimeCode {
+        data: u8,
+    },
+
+    /// The position within a song, in sixteenth notes
+    SongPosition {
+        position: u16,
+    },
+
+    /// A song was selected
+    SongSelect {
+        song: u8,
+    },
+
+    /// Analog synthesizers should tune their oscillators
+    TuneRequest,
+
+    /// Sent 24 times per quarter note while the transport is running
+    Clock,
+
+    /// Transport started from the beginning of the song
+    Start,
+
+    /// Transport resumed from where it was stopped
+    Continue,
+
+    /// Transport stopped
+    Stop,
+
+    /// A keepalive sent by some devices every 300ms
+    ActiveSensing,
+
+    /// All devices should reset to their power-up state
+    SystemReset,
 }
 
-#[derive(Debug, PartialOrd, PartialEq, Copy, Clone)]
+impl fmt::Display for MidiEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MidiEvent::SysEx(ref data) => {
+                write!(f, "SysEx")?;
+                for byte in data {
+                    write!(f, " {:02X}", byte)?;
+                }
+                Ok(())
+            }
+            ref other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+/// The value reported by a pitch wheel at rest
+pub const PITCH_BEND_CENTER: u16 = 0x2000;
+
+#[derive(Debug, PartialOrd, PartialEq, Eq, Hash, Copy, Clone)]
 #[allow(dead_code)]
 pub enum MidiNote {
     Cn = 0,
@@ -141,7 +222,7 @@ pub enum MidiNote {
 pub struct MidiMessage {
     event: MidiEvent,
     channel: u8,
-    note: MidiNote,
+    note: Option<MidiNote>,
     velocity: u8,
 }
 
@@ -159,7 +240,7 @@ impl MidiNote {
             return Err(MidiError::NoteOutOfRange);
         }
         use std::mem;
-        Ok(unsafe { mem::transmute(val) })
+        Ok(unsafe { mem::transmute::<u8, MidiNote>(val) })
     }
 
     #[allow(clippy::cognitive_complexity)]
@@ -431,10 +512,23 @@ impl MidiNote {
     pub fn index(self) -> u8 {
         self as u8
     }
+
+    /// The note `semitones` above this one (or below, if negative), or
+    /// `None` if that's outside the MIDI note range.
+    pub fn checked_add(self, semitones: i8) -> Option<MidiNote> {
+        let val = i16::from(self.index()) + i16::from(semitones);
+        if val < 0 {
+            return None;
+        }
+        MidiNote::new(val as u8).ok()
+    }
 }
 
 impl MidiMessage {
     pub fn new(message: &[u8]) -> Result<MidiMessage, MidiError> {
+        if message.is_empty() {
+            return Err(MidiError::TooShort);
+        }
         match message[0] & 0xf0 {
             0x80 => {
                 if message.len() < 3 {
@@ -443,7 +537,7 @@ impl MidiMessage {
                     Ok(MidiMessage {
                         event: MidiEvent::NoteOff,
                         channel: message[0] & 0x0f,
-                        note: MidiNote::new(message[1] & 0x7f)?,
+                        note: Some(MidiNote::new(message[1] & 0x7f)?),
                         velocity: message[2] & 0x7f,
                     })
                 }
@@ -461,24 +555,336 @@ impl MidiMessage {
                     Ok(MidiMessage {
                         event,
                         channel: message[0] & 0x0f,
-                        note: MidiNote::new(message[1] & 0x7f)?,
+                        note: Some(MidiNote::new(message[1] & 0x7f)?),
                         velocity,
                     })
                 }
             }
+            0xb0 => {
+                if message.len() < 3 {
+                    Err(MidiError::TooShort)
+                } else {
+                    Ok(MidiMessage {
+                        event: MidiEvent::ControlChange {
+                            controller: message[1] & 0x7f,
+                            value: message[2] & 0x7f,
+                        },
+                        channel: message[0] & 0x0f,
+                        note: None,
+                        velocity: 0,
+                    })
+                }
+            }
+            0xc0 => {
+                if message.len() < 2 {
+                    Err(MidiError::TooShort)
+                } else {
+                    Ok(MidiMessage {
+                        event: MidiEvent::ProgramChange {
+                            program: message[1] & 0x7f,
+                        },
+                        channel: message[0] & 0x0f,
+                        note: None,
+                        velocity: 0,
+                    })
+                }
+            }
+            0xd0 => {
+                if message.len() < 2 {
+                    Err(MidiError::TooShort)
+                } else {
+                    Ok(MidiMessage {
+                        event: MidiEvent::ChannelPressure {
+                            pressure: message[1] & 0x7f,
+                        },
+                        channel: message[0] & 0x0f,
+                        note: None,
+                        velocity: 0,
+                    })
+                }
+            }
+            0xe0 => {
+                if message.len() < 3 {
+                    Err(MidiError::TooShort)
+                } else {
+                    // Least significant 7 bits come first
+                    let value = (u16::from(message[2] & 0x7f) << 7) | u16::from(message[1] & 0x7f);
+                    Ok(MidiMessage {
+                        event: MidiEvent::PitchBend { value },
+                        channel: message[0] & 0x0f,
+                        note: None,
+                        velocity: 0,
+                    })
+                }
+            }
+            0xf0 => MidiMessage::new_system(message),
             _ => Err(MidiError::Unimplemented(message[0])),
         }
     }
 
+    /// Decode a system message.  These aren't tied to a channel, so they
+    /// always report channel 0.
+    fn new_system(message: &[u8]) -> Result<MidiMessage, MidiError> {
+        let needed = match message[0] {
+            0xf0 => 2,
+            0xf2 => 3,
+            0xf1 | 0xf3 => 2,
+            _ => 1,
+        };
+        if message.len() < needed {
+            return Err(MidiError::TooShort);
+        }
+        let event = match message[0] {
+            0xf0 => MidiEvent::SysEx(message.to_vec()),
+            0xf1 => MidiEvent::TimeCode {
+                data: message[1] & 0x7f,
+            },
+            0xf2 => MidiEvent::SongPosition {
+                position: (u16::from(message[2] & 0x7f) << 7) | u16::from(message[1] & 0x7f),
+            },
+            0xf3 => MidiEvent::SongSelect {
+                song: message[1] & 0x7f,
+            },
+            0xf6 => MidiEvent::TuneRequest,
+            0xf8 => MidiEvent::Clock,
+            0xfa => MidiEvent::Start,
+            0xfb => MidiEvent::Continue,
+            0xfc => MidiEvent::Stop,
+            0xfe => MidiEvent::ActiveSensing,
+            0xff => MidiEvent::SystemReset,
+            other => return Err(MidiError::Unimplemented(other)),
+        };
+        Ok(MidiMessage {
+            event,
+            channel: 0,
+            note: None,
+            velocity: 0,
+        })
+    }
+
     pub fn channel(&self) -> u8 {
         self.channel
     }
 
-    pub fn note(&self) -> &MidiNote {
-        &self.note
+    /// The note this message refers to, or `None` for controller messages.
+    pub fn note(&self) -> Option<MidiNote> {
+        self.note
     }
 
     pub fn event(&self) -> &MidiEvent {
         &self.event
     }
+
+    /// How hard a note was struck, from 1 to 127.  This is 0 for messages
+    /// other than NoteOn and NoteOff.
+    pub fn velocity(&self) -> u8 {
+        self.velocity
+    }
+}
+
+/// The total length of a message, including its status byte, or `None` for
+/// SysEx, which runs until an End of Exclusive byte.
+fn message_length(status: u8) -> Option<usize> {
+    match status {
+        0x80..=0xbf | 0xe0..=0xef => Some(3),
+        0xc0..=0xdf => Some(2),
+        0xf0 => None,
+        0xf1 | 0xf3 => Some(2),
+        0xf2 => Some(3),
+        _ => Some(1),
+    }
+}
+
+/// A stateful decoder for a stream of MIDI bytes from a single connection.
+/// It handles running status, realtime bytes interleaved within other
+/// messages, and packets containing more than one message.
+#[derive(Default)]
+pub struct MidiParser {
+    /// The last channel status byte, reused when a message omits its status.
+    running_status: Option<u8>,
+
+    /// The message currently being assembled, starting with its status byte.
+    pending: Vec<u8>,
+}
+
+impl MidiParser {
+    pub fn new() -> MidiParser {
+        MidiParser::default()
+    }
+
+    /// Feed a packet of bytes to the decoder, returning every message that
+    /// was completed by it.  Partial messages are kept for the next packet.
+    pub fn parse(&mut self, data: &[u8]) -> Vec<Result<MidiMessage, MidiError>> {
+        let mut messages = vec![];
+        for &byte in data {
+            match byte {
+                // Realtime bytes may appear anywhere, even in the middle of
+                // another message, and don't disturb it.
+                0xf8..=0xff => messages.push(MidiMessage::new(&[byte])),
+
+                // End of Exclusive finishes a SysEx message.
+                0xf7 => {
+                    if self.pending.first() == Some(&0xf0) {
+                        self.pending.push(byte);
+                        messages.push(MidiMessage::new(&self.pending));
+                    }
+                    self.pending.clear();
+                }
+
+                // System common messages cancel running status.
+                0xf0..=0xf6 => {
+                    self.running_status = None;
+                    self.pending.clear();
+                    self.pending.push(byte);
+                }
+
+                0x80..=0xef => {
+                    self.running_status = Some(byte);
+                    self.pending.clear();
+                    self.pending.push(byte);
+                }
+
+                // Data byte
+                _ => {
+                    if self.pending.is_empty() {
+                        match self.running_status {
+                            Some(status) => self.pending.push(status),
+                            // Data with no status to attach it to
+                            None => continue,
+                        }
+                    }
+                    self.pending.push(byte);
+                }
+            }
+
+            if let Some(&status) = self.pending.first() {
+                if message_length(status) == Some(self.pending.len()) {
+                    messages.push(MidiMessage::new(&self.pending));
+                    self.pending.clear();
+                }
+            }
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode `packets` one after another, summarizing each message.
+    fn parse_all(packets: &[&[u8]]) -> Vec<(MidiEvent, u8, Option<MidiNote>, u8)> {
+        let mut parser = MidiParser::new();
+        packets
+            .iter()
+            .flat_map(|packet| parser.parse(packet))
+            .map(|msg| {
+                let msg = msg.unwrap();
+                (
+                    msg.event().clone(),
+                    msg.channel(),
+                    msg.note(),
+                    msg.velocity(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn running_status() {
+        let messages = parse_all(&[&[0x91, 60, 100, 62, 90, 64, 80]]);
+        assert_eq!(
+            messages,
+            vec![
+                (MidiEvent::NoteOn, 1, Some(MidiNote::C4), 100),
+                (MidiEvent::NoteOn, 1, Some(MidiNote::D4), 90),
+                (MidiEvent::NoteOn, 1, Some(MidiNote::E4), 80),
+            ]
+        );
+    }
+
+    #[test]
+    fn system_common_cancels_running_status() {
+        let messages = parse_all(&[&[0xb0, 64, 127, 0xf3, 5, 64, 0]]);
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    MidiEvent::ControlChange {
+                        controller: 64,
+                        value: 127
+                    },
+                    0,
+                    None,
+                    0
+                ),
+                (MidiEvent::SongSelect { song: 5 }, 0, None, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn realtime_inside_message() {
+        let messages = parse_all(&[&[0x90, 0xf8, 60, 0xfa, 100]]);
+        assert_eq!(
+            messages,
+            vec![
+                (MidiEvent::Clock, 0, None, 0),
+                (MidiEvent::Start, 0, None, 0),
+                (MidiEvent::NoteOn, 0, Some(MidiNote::C4), 100),
+            ]
+        );
+    }
+
+    #[test]
+    fn message_split_across_packets() {
+        let messages = parse_all(&[&[0xe2], &[0x00], &[0x40, 0x92, 60], &[0x7f]]);
+        assert_eq!(
+            messages,
+            vec![
+                (MidiEvent::PitchBend { value: 8192 }, 2, None, 0),
+                (MidiEvent::NoteOn, 2, Some(MidiNote::C4), 127),
+            ]
+        );
+    }
+
+    #[test]
+    fn sysex_framing() {
+        let messages = parse_all(&[&[0xf0, 0x7e, 0x7f], &[0xf8, 0x06, 0x01, 0xf7, 0xfc]]);
+        assert_eq!(
+            messages,
+            vec![
+                (MidiEvent::Clock, 0, None, 0),
+                (
+                    MidiEvent::SysEx(vec![0xf0, 0x7e, 0x7f, 0x06, 0x01, 0xf7]),
+                    0,
+                    None,
+                    0
+                ),
+                (MidiEvent::Stop, 0, None, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn checked_add() {
+        assert_eq!(MidiNote::C4.checked_add(12), Some(MidiNote::C5));
+        assert_eq!(MidiNote::C4.checked_add(-12), Some(MidiNote::C3));
+        assert_eq!(MidiNote::Cn.checked_add(0), Some(MidiNote::Cn));
+        assert_eq!(MidiNote::Cn.checked_add(-1), None);
+        assert_eq!(MidiNote::G9.checked_add(0), Some(MidiNote::G9));
+        assert_eq!(MidiNote::G9.checked_add(1), None);
+        assert_eq!(MidiNote::G9.checked_add(i8::MIN), None);
+        assert_eq!(MidiNote::Cn.checked_add(i8::MAX), Some(MidiNote::G9));
+    }
+
+    #[test]
+    fn stray_bytes_are_dropped() {
+        // Data with no status, and an End of Exclusive with no SysEx
+        let messages = parse_all(&[&[0x40, 0x41, 0xf7, 0xc0, 3]]);
+        assert_eq!(
+            messages,
+            vec![(MidiEvent::ProgramChange { program: 3 }, 0, None, 0)]
+        );
+    }
 }
@@ -1,7 +1,9 @@
-use crate::midi::MidiNote;
-use enigo::Key;
+use crate::mappingfile::{self, MappingsError};
+use crate::midi::{MidiEvent, MidiNote};
+use enigo::{Key, MouseButton as EnigoButton};
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Result};
+use std::io::BufReader;
 
 /// Proxy for Enigo::Key, since that variant isn't cloneable
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -69,7 +71,8 @@ pub enum KbdKey {
     F12,
     /// keyboard layout dependent key
     Layout(char),
-    /// raw keycode eg 0x38
+    /// raw keycode eg 0x38, passed straight to the output: a virtual key
+    /// on Windows, a keycode on macOS, or an evdev `KEY_*` code with uinput
     Raw(u16),
 }
 
@@ -110,9 +113,113 @@ impl KbdKey {
             KbdKey::Raw(c) => Key::Raw(c),
         }
     }
+
+    /// Parse a key from its variant name (e.g. "Shift" or "F5").
+    /// A single character is treated as a keyboard layout dependent key, and
+    /// characters that can't be written directly (such as space) can be
+    /// given as e.g. "U+0020".  Raw keycodes are written as "Raw(0x38)".
+    pub fn from_name(name: &str) -> Option<KbdKey> {
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Some(KbdKey::Layout(c));
+        }
+        if let Some(code) = name.strip_prefix("U+") {
+            let code = u32::from_str_radix(code, 16).ok()?;
+            return std::char::from_u32(code).map(KbdKey::Layout);
+        }
+        if let Some(code) = name.strip_prefix("Raw(").and_then(|c| c.strip_suffix(')')) {
+            let code = match code.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+                None => code.parse::<u16>().ok()?,
+            };
+            return Some(KbdKey::Raw(code));
+        }
+        let key = match name {
+            "Return" => KbdKey::Return,
+            "Tab" => KbdKey::Tab,
+            "Space" => KbdKey::Space,
+            "Backspace" => KbdKey::Backspace,
+            "Escape" => KbdKey::Escape,
+            "Meta" => KbdKey::Meta,
+            "Shift" => KbdKey::Shift,
+            "CapsLock" => KbdKey::CapsLock,
+            "Alt" => KbdKey::Alt,
+            "Option" => KbdKey::Option,
+            "Control" => KbdKey::Control,
+            "Home" => KbdKey::Home,
+            "PageUp" => KbdKey::PageUp,
+            "PageDown" => KbdKey::PageDown,
+            "LeftArrow" => KbdKey::LeftArrow,
+            "RightArrow" => KbdKey::RightArrow,
+            "DownArrow" => KbdKey::DownArrow,
+            "UpArrow" => KbdKey::UpArrow,
+            "F1" => KbdKey::F1,
+            "F2" => KbdKey::F2,
+            "F3" => KbdKey::F3,
+            "F4" => KbdKey::F4,
+            "F5" => KbdKey::F5,
+            "F6" => KbdKey::F6,
+            "F7" => KbdKey::F7,
+            "F8" => KbdKey::F8,
+            "F9" => KbdKey::F9,
+            "F10" => KbdKey::F10,
+            "F11" => KbdKey::F11,
+            "F12" => KbdKey::F12,
+            _ => return None,
+        };
+        Some(key)
+    }
 }
 
-#[derive(Clone, Debug)]
+/// Writes the key the way `KbdKey::from_name()` reads it.
+impl fmt::Display for KbdKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KbdKey::Layout(c) if c.is_whitespace() || c.is_control() || c == '|' || c == '"' => {
+                write!(f, "U+{:04X}", c as u32)
+            }
+            KbdKey::Layout(c) => write!(f, "{}", c),
+            KbdKey::Raw(code) => write!(f, "Raw(0x{:x})", code),
+            ref named => write!(f, "{:?}", named),
+        }
+    }
+}
+
+/// A mouse button.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+}
+
+impl MouseButton {
+    pub fn to_enigo_button(self) -> EnigoButton {
+        match self {
+            MouseButton::Left => EnigoButton::Left,
+            MouseButton::Middle => EnigoButton::Middle,
+            MouseButton::Right => EnigoButton::Right,
+        }
+    }
+
+    /// Look up a button by the name it's displayed with, such as "Left".
+    pub fn from_name(name: &str) -> Option<MouseButton> {
+        match name {
+            "Left" => Some(MouseButton::Left),
+            "Middle" => Some(MouseButton::Middle),
+            "Right" => Some(MouseButton::Right),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MouseButton {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Event {
     /// Insert a Delay for a specified number of ms
     Delay(u64),
@@ -123,21 +230,333 @@ pub enum Event {
     /// Release a key
     KeyUp(KbdKey),
 
+    /// Type a string, which may contain any Unicode characters, without
+    /// changing which keys are held.
+    Text(String),
+
+    /// Press a mouse button
+    MouseDown(MouseButton),
+
+    /// Release a mouse button
+    MouseUp(MouseButton),
+
+    /// Move the mouse pointer by a number of pixels right and down
+    MouseMove(i32, i32),
+
+    /// Move the mouse pointer to a position, in pixels from the top left of
+    /// the screen
+    MouseMoveTo(i32, i32),
+
+    /// Scroll by a number of steps right and down
+    Scroll(i32, i32),
+
     /// Keep a key held down during this script.
     /// Note that the key may be continued to be held down until a script
     /// with no NoteMod is encountered.
     NoteMod(Option<KbdKey>),
+
+    /// Hold a modifier through NoteMods that ask for it or for none, until
+    /// `Unlatch`: the given one, or with `None`, whichever one the next
+    /// NoteMod asks for.
+    Latch(Option<KbdKey>),
+
+    /// Let each NoteMod pick its own modifier again, releasing the latched
+    /// one.  Only the device that latched can unlatch.
+    Unlatch,
+
+    /// Switch to the named mapping layer, releasing any held keys.
+    SelectLayer(String),
+
+    /// Shift notes played from now on by a number of semitones, on top of
+    /// any shift already in place.  An octave is 12.
+    Transpose(i8),
+
+    /// Stop shifting notes.
+    TransposeReset,
+}
+
+impl Event {
+    /// Parse an event written the way it is displayed, such as
+    /// "KeyDown(Shift)", "Delay(150)", "NoteMod(None)" or "Transpose(-12)".
+    /// Text is written in double quotes, as in `Text("café")`.
+    pub fn parse(txt: &str) -> Option<Event> {
+        let open = txt.find('(')?;
+        let arg = txt[open + 1..].strip_suffix(')')?;
+        let event = match &txt[..open] {
+            "Delay" => Event::Delay(arg.parse::<u64>().ok()?),
+            "KeyDown" => Event::KeyDown(KbdKey::from_name(arg)?),
+            "KeyUp" => Event::KeyUp(KbdKey::from_name(arg)?),
+            "Text" => Event::Text(parse_quoted(arg)?),
+            "MouseDown" => Event::MouseDown(MouseButton::from_name(arg)?),
+            "MouseUp" => Event::MouseUp(MouseButton::from_name(arg)?),
+            "MouseMove" => {
+                let (x, y) = parse_pair(arg)?;
+                Event::MouseMove(x, y)
+            }
+            "MouseMoveTo" => {
+                let (x, y) = parse_pair(arg)?;
+                Event::MouseMoveTo(x, y)
+            }
+            "Scroll" => {
+                let (x, y) = parse_pair(arg)?;
+                Event::Scroll(x, y)
+            }
+            "NoteMod" if arg == "None" => Event::NoteMod(None),
+            "NoteMod" => Event::NoteMod(Some(KbdKey::from_name(arg)?)),
+            "Latch" if arg == "On" => Event::Latch(None),
+            "Latch" if arg == "Off" => Event::Unlatch,
+            "Latch" => Event::Latch(Some(KbdKey::from_name(arg)?)),
+            "SelectLayer" if !arg.is_empty() => Event::SelectLayer(arg.to_owned()),
+            "Transpose" if arg == "Reset" => Event::TransposeReset,
+            "Transpose" => Event::Transpose(arg.parse::<i8>().ok()?),
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+/// Parse a string written in double quotes, where `\"` is a quote, `\\` is a
+/// backslash and `\n` is a new line.
+fn parse_quoted(txt: &str) -> Option<String> {
+    let inner = txt.strip_prefix('"')?.strip_suffix('"')?;
+    let mut s = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => s.push('\n'),
+                c @ '"' | c @ '\\' => s.push(c),
+                _ => return None,
+            },
+            '"' => return None,
+            c => s.push(c),
+        }
+    }
+    Some(s)
+}
+
+/// Parse a pair of numbers written as "x,y".
+fn parse_pair(txt: &str) -> Option<(i32, i32)> {
+    let comma = txt.find(',')?;
+    let x = txt[..comma].parse::<i32>().ok()?;
+    let y = txt[comma + 1..].parse::<i32>().ok()?;
+    Some((x, y))
+}
+
+/// Write a string in double quotes, the way `parse_quoted()` reads it.
+fn quote(txt: &str) -> String {
+    let mut s = String::from("\"");
+    for c in txt.chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            c => s.push(c),
+        }
+    }
+    s.push('"');
+    s
+}
+
+/// Writes the event the way `Event::parse()` reads it.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Event::Delay(msecs) => write!(f, "Delay({})", msecs),
+            Event::KeyDown(ref k) => write!(f, "KeyDown({})", k),
+            Event::KeyUp(ref k) => write!(f, "KeyUp({})", k),
+            Event::Text(ref text) => write!(f, "Text({})", quote(text)),
+            Event::MouseDown(button) => write!(f, "MouseDown({})", button),
+            Event::MouseUp(button) => write!(f, "MouseUp({})", button),
+            Event::MouseMove(x, y) => write!(f, "MouseMove({},{})", x, y),
+            Event::MouseMoveTo(x, y) => write!(f, "MouseMoveTo({},{})", x, y),
+            Event::Scroll(x, y) => write!(f, "Scroll({},{})", x, y),
+            Event::NoteMod(None) => write!(f, "NoteMod(None)"),
+            Event::NoteMod(Some(ref k)) => write!(f, "NoteMod({})", k),
+            Event::Latch(None) => write!(f, "Latch(On)"),
+            Event::Latch(Some(ref k)) => write!(f, "Latch({})", k),
+            Event::Unlatch => write!(f, "Latch(Off)"),
+            Event::SelectLayer(ref name) => write!(f, "SelectLayer({})", name),
+            Event::Transpose(semitones) => write!(f, "Transpose({:+})", semitones),
+            Event::TransposeReset => write!(f, "Transpose(Reset)"),
+        }
+    }
+}
+
+/// An alternate pair of sequences used when a note is struck with a
+/// velocity inside a given range.
+#[derive(Clone, Debug)]
+pub struct VelocityLayer {
+    /// The lowest velocity that selects this layer.
+    min: u8,
+
+    /// The highest velocity that selects this layer.
+    max: u8,
+
+    /// A sequence to call when the note is pressed.
+    pub on: Vec<Event>,
+
+    /// A sequence to call when the note is released.
+    pub off: Vec<Event>,
+}
+
+impl VelocityLayer {
+    pub fn new(min: u8, max: u8) -> VelocityLayer {
+        VelocityLayer {
+            min,
+            max,
+            on: vec![],
+            off: vec![],
+        }
+    }
+
+    pub fn min(&self) -> u8 {
+        self.min
+    }
+
+    pub fn max(&self) -> u8 {
+        self.max
+    }
+
+    fn contains(&self, velocity: u8) -> bool {
+        velocity >= self.min && velocity <= self.max
+    }
+}
+
+/// How long, in milliseconds, a note must be held before it counts as a
+/// long press, unless its mapping says otherwise.
+pub const DEFAULT_LONG_PRESS_MS: u64 = 250;
+
+/// An alternate pair of sequences used when a note is held down for a
+/// while, rather than tapped.
+#[derive(Clone, Debug)]
+pub struct LongPress {
+    /// How long, in milliseconds, the note must be held.
+    threshold: u64,
+
+    /// A sequence to call once the note has been held for long enough.
+    pub on: Vec<Event>,
+
+    /// A sequence to call when the note is released after a long press.
+    pub off: Vec<Event>,
+}
+
+impl LongPress {
+    pub fn new(threshold: u64) -> LongPress {
+        LongPress {
+            threshold,
+            on: vec![],
+            off: vec![],
+        }
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold
+    }
+}
+
+/// How long, in milliseconds, to wait after a tap for the next tap of a
+/// double or triple tap, unless its mapping says otherwise.
+pub const DEFAULT_TAP_WINDOW_MS: u64 = 300;
+
+/// An alternate pair of sequences used when a note is tapped several times
+/// in quick succession.
+#[derive(Clone, Debug)]
+pub struct MultiTap {
+    /// The number of taps, such as 2 for a double tap.
+    count: u8,
+
+    /// How long, in milliseconds, each tap can come after the one before.
+    window: u64,
+
+    /// A sequence to call when the last tap is pressed.
+    pub on: Vec<Event>,
+
+    /// A sequence to call when the last tap is released.
+    pub off: Vec<Event>,
+}
+
+impl MultiTap {
+    pub fn new(count: u8, window: u64) -> MultiTap {
+        MultiTap {
+            count,
+            window,
+            on: vec![],
+            off: vec![],
+        }
+    }
+
+    pub fn count(&self) -> u8 {
+        self.count
+    }
+
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+}
+
+/// Returns `true` if `name` contains `*` or `?`, and so is a pattern rather
+/// than the name of one instrument.
+pub fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+/// Returns `true` if the instrument `name` matches `pattern`, where `*`
+/// matches any run of characters and `?` matches any one character.
+pub fn name_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Where to resume after the most recent `*`, if there's been one: the
+    // pattern just past it, and the next name character it should swallow.
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
+/// A mapping from a note, or a range of notes, to sequences.
+///
+/// When several mappings in a layer match a note, the most specific one
+/// wins: a mapping for a particular instrument beats one for a pattern of
+/// instrument names, which beats one for any instrument, then a particular
+/// channel beats any channel, and then a smaller range of notes beats a
+/// larger one.  If that's still a tie, the mapping that was added first
+/// wins.
 #[derive(Clone, Debug)]
 pub struct NoteMapping {
-    /// The source note that triggered this event.
+    /// The source note that triggered this event, or the lowest note of a
+    /// range.
     note: MidiNote,
 
-    /// The source channel.  0 is a good default here.
-    channel: u8,
+    /// The highest note of the range.  This is `note` for a single note.
+    last_note: MidiNote,
+
+    /// The source channel, or `None` for any channel.
+    channel: Option<u8>,
 
-    /// The name of the instrument that we're looking for.
+    /// The name of the instrument that we're looking for, or `None` for
+    /// any instrument.  This can be a pattern, where `*` matches any run of
+    /// characters and `?` matches any one character.
     instrument_name: Option<String>,
 
     /// A sequence to call when the note is pressed.
@@ -145,19 +564,146 @@ pub struct NoteMapping {
 
     /// A sequence to call when the note is released.
     pub off: Vec<Event>,
+
+    /// Sequences to use instead of `on` and `off` when the note is struck
+    /// within a particular velocity range.  The first matching layer wins.
+    pub velocity_layers: Vec<VelocityLayer>,
+
+    /// How long, in milliseconds, a key pressed by this mapping may be held
+    /// before it's considered stuck and released.
+    pub max_hold: Option<u64>,
+
+    /// Sequences to use when the note is held down rather than tapped.  If
+    /// this is set, nothing happens when the note is pressed.  Instead the
+    /// usual sequences both run when a short press is released, and these
+    /// run once the note has been held down long enough.  Only the first
+    /// tap of a multiple tap can be a long press.
+    pub long_press: Option<LongPress>,
+
+    /// Sequences to use when the note is tapped several times in a row.
+    pub taps: Vec<MultiTap>,
 }
 
 impl NoteMapping {
     pub fn new(note: MidiNote, channel: u8, instrument_name: Option<String>) -> NoteMapping {
+        NoteMapping::with_range(note, note, Some(channel), instrument_name)
+    }
+
+    /// Create a mapping for every note from `note` to `last_note`, on
+    /// `channel` or on any channel if that's `None`.
+    pub fn with_range(
+        note: MidiNote,
+        last_note: MidiNote,
+        channel: Option<u8>,
+        instrument_name: Option<String>,
+    ) -> NoteMapping {
         NoteMapping {
             note,
+            last_note,
             channel,
             instrument_name,
             on: vec![],
             off: vec![],
+            velocity_layers: vec![],
+            max_hold: None,
+            long_press: None,
+            taps: vec![],
         }
     }
 
+    pub fn note(&self) -> MidiNote {
+        self.note
+    }
+
+    pub fn last_note(&self) -> MidiNote {
+        self.last_note
+    }
+
+    pub fn channel(&self) -> Option<u8> {
+        self.channel
+    }
+
+    pub fn instrument_name(&self) -> Option<&str> {
+        self.instrument_name.as_deref()
+    }
+
+    /// Returns `true` if this mapping applies to `note` on `channel` from
+    /// the instrument `instrument_name`.
+    pub fn matches(&self, note: MidiNote, channel: u8, instrument_name: Option<&str>) -> bool {
+        (self.note as u8) <= (note as u8)
+            && (note as u8) <= (self.last_note as u8)
+            && self.channel.is_none_or(|c| c == channel)
+            && self.instrument_name.as_deref().is_none_or(|pattern| {
+                instrument_name.is_some_and(|name| name_matches(pattern, name))
+            })
+    }
+
+    /// How specific this mapping is.  Higher values win.
+    fn specificity(&self) -> (u8, bool, i16) {
+        let instrument = match self.instrument_name {
+            None => 0,
+            Some(ref name) if is_pattern(name) => 1,
+            Some(_) => 2,
+        };
+        (
+            instrument,
+            self.channel.is_some(),
+            -(i16::from(self.last_note as u8) - i16::from(self.note as u8)),
+        )
+    }
+
+    /// The sequence to call when the note is pressed with `velocity`.
+    pub fn on_sequence(&self, velocity: u8) -> &[Event] {
+        match self.velocity_layers.iter().find(|l| l.contains(velocity)) {
+            Some(layer) => &layer.on,
+            None => &self.on,
+        }
+    }
+
+    /// The sequence to call when a note that was pressed with `velocity` is
+    /// released.  Note that this is the velocity of the NoteOn, so the
+    /// release matches whatever the press did.
+    pub fn off_sequence(&self, velocity: u8) -> &[Event] {
+        match self.velocity_layers.iter().find(|l| l.contains(velocity)) {
+            Some(layer) => &layer.off,
+            None => &self.off,
+        }
+    }
+
+    /// Returns `true` if the note has a long press or multiple taps, and so
+    /// has to wait to see how it's played.
+    pub fn has_gestures(&self) -> bool {
+        self.long_press.is_some() || !self.taps.is_empty()
+    }
+
+    /// The most taps in a row that do something different.
+    pub fn max_taps(&self) -> u8 {
+        self.taps.iter().map(MultiTap::count).max().unwrap_or(1)
+    }
+
+    /// How long, in milliseconds, to wait for another tap, if there are
+    /// any multiple taps.
+    pub fn tap_window(&self) -> Option<u64> {
+        self.taps.iter().map(MultiTap::window).max()
+    }
+
+    /// The sequences to call when the note is pressed and released after
+    /// being tapped `count` times in a row, with the first tap struck with
+    /// `velocity`.  Counts without a sequence of their own repeat the
+    /// single tap sequences.
+    pub fn tap_sequences(&self, count: u8, velocity: u8) -> (Vec<Event>, Vec<Event>) {
+        if let Some(tap) = self.taps.iter().find(|tap| tap.count == count) {
+            return (tap.on.clone(), tap.off.clone());
+        }
+        let mut on = vec![];
+        for _ in 1..count {
+            on.extend_from_slice(self.on_sequence(velocity));
+            on.extend_from_slice(self.off_sequence(velocity));
+        }
+        on.extend_from_slice(self.on_sequence(velocity));
+        (on, self.off_sequence(velocity).to_vec())
+    }
+
     pub fn down_event(key: char, modifier: Option<KbdKey>, _delay: Option<u64>) -> Vec<Event> {
         let mut v = vec![];
 
@@ -188,9 +734,372 @@ impl NoteMapping {
     }
 }
 
-#[derive(Default)]
-pub struct NoteMappings {
+/// A mapping from a MIDI controller (e.g. the sustain pedal, CC64) to a
+/// pair of sequences.  The controller is considered "on" while its value is
+/// at or above `threshold`, and the sequences fire when it crosses over.
+#[derive(Clone, Debug)]
+pub struct ControlMapping {
+    /// The controller number, e.g. 64 for the sustain pedal.
+    controller: u8,
+
+    /// The source channel.
+    channel: u8,
+
+    /// Values at or above this turn the controller "on".
+    threshold: u8,
+
+    /// A sequence to call when the controller goes above the threshold.
+    pub on: Vec<Event>,
+
+    /// A sequence to call when the controller drops below the threshold.
+    pub off: Vec<Event>,
+
+    /// How long, in milliseconds, a key pressed by this mapping may be held
+    /// before it's considered stuck and released.
+    pub max_hold: Option<u64>,
+}
+
+impl ControlMapping {
+    pub fn new(controller: u8, channel: u8, threshold: u8) -> ControlMapping {
+        ControlMapping {
+            controller,
+            channel,
+            threshold,
+            on: vec![],
+            off: vec![],
+            max_hold: None,
+        }
+    }
+
+    pub fn controller(&self) -> u8 {
+        self.controller
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    /// Returns `true` if `value` puts this controller in its "on" state.
+    pub fn is_on(&self, value: u8) -> bool {
+        value >= self.threshold
+    }
+}
+
+/// A continuously-varying input that can drive repeated keypresses or
+/// mouse movement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContinuousSource {
+    /// The pitch wheel.  Bending down is negative, bending up is positive.
+    PitchBend,
+
+    /// Channel aftertouch, which is always positive.
+    ChannelPressure,
+
+    /// A controller such as a knob or joystick, which is at rest at 64.
+    /// Values below that are negative, and above it are positive.
+    Control(u8),
+}
+
+/// Which way a continuous input moves the mouse.  Positive amounts move
+/// the pointer right or up, or scroll right or up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MouseAxis {
+    MoveX,
+    MoveY,
+    ScrollX,
+    ScrollY,
+}
+
+impl MouseAxis {
+    /// Look up an axis by the name it's displayed with, such as "MoveX".
+    pub fn from_name(name: &str) -> Option<MouseAxis> {
+        match name {
+            "MoveX" => Some(MouseAxis::MoveX),
+            "MoveY" => Some(MouseAxis::MoveY),
+            "ScrollX" => Some(MouseAxis::ScrollX),
+            "ScrollY" => Some(MouseAxis::ScrollY),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MouseAxis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// A mapping that turns a continuous input into a stream of keypresses,
+/// repeating faster the further the input is from rest.  If `mouse` is set,
+/// it moves the mouse or scrolls instead, again going faster the further
+/// the input is from rest.
+#[derive(Clone, Debug)]
+pub struct ContinuousMapping {
+    /// The input that drives this mapping.
+    source: ContinuousSource,
+
+    /// The source channel.
+    channel: u8,
+
+    /// The key to repeat while the input is negative.
+    pub negative: Option<KbdKey>,
+
+    /// The key to repeat while the input is positive.
+    pub positive: Option<KbdKey>,
+
+    /// The direction to move the mouse in, instead of pressing keys.
+    pub mouse: Option<MouseAxis>,
+
+    /// The number of presses per second when the input is at its extreme,
+    /// or for the mouse, the number of pixels or scroll steps per second.
+    rate: f32,
+
+    /// Inputs closer to rest than this are ignored, since wheels rarely
+    /// return exactly to center.
+    dead_zone: f32,
+}
+
+impl ContinuousMapping {
+    pub fn new(source: ContinuousSource, channel: u8, rate: f32) -> ContinuousMapping {
+        ContinuousMapping {
+            source,
+            channel,
+            negative: None,
+            positive: None,
+            mouse: None,
+            rate,
+            dead_zone: 0.05,
+        }
+    }
+
+    pub fn source(&self) -> ContinuousSource {
+        self.source
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    /// Returns `true` if `amount` is close enough to rest to be ignored.
+    pub fn is_at_rest(&self, amount: f32) -> bool {
+        amount.abs() < self.dead_zone
+    }
+
+    /// The key to repeat for a given amount (-1.0 to 1.0), if any.
+    pub fn key_for(&self, amount: f32) -> Option<&KbdKey> {
+        if self.is_at_rest(amount) {
+            None
+        } else if amount < 0.0 {
+            self.negative.as_ref()
+        } else {
+            self.positive.as_ref()
+        }
+    }
+}
+
+/// Transport messages that can trigger a sequence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Start,
+    Continue,
+    Stop,
+}
+
+impl Transport {
+    /// The transport message corresponding to a MIDI event, if any.
+    pub fn from_event(event: &MidiEvent) -> Option<Transport> {
+        match *event {
+            MidiEvent::Start => Some(Transport::Start),
+            MidiEvent::Continue => Some(Transport::Continue),
+            MidiEvent::Stop => Some(Transport::Stop),
+            _ => None,
+        }
+    }
+}
+
+/// A mapping from a transport message (e.g. a sequencer pressing Play) to
+/// a sequence.  Transport messages aren't tied to a channel.
+#[derive(Clone, Debug)]
+pub struct TransportMapping {
+    /// The transport message that triggers this mapping.
+    transport: Transport,
+
+    /// A sequence to call when the transport message arrives.
+    pub on: Vec<Event>,
+}
+
+impl TransportMapping {
+    pub fn new(transport: Transport) -> TransportMapping {
+        TransportMapping {
+            transport,
+            on: vec![],
+        }
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+}
+
+/// The default time, in milliseconds, for all of a chord's notes to arrive.
+pub const DEFAULT_CHORD_WINDOW_MS: u64 = 50;
+
+/// A mapping from several notes played together to a single sequence.
+/// The notes must all be pressed within `window` milliseconds of the first.
+#[derive(Clone, Debug)]
+pub struct ChordMapping {
+    /// The notes in the chord, lowest first.
+    notes: Vec<MidiNote>,
+
+    /// The source channel.
+    channel: u8,
+
+    /// How long, in milliseconds, to wait for the rest of the chord.
+    window: u64,
+
+    /// A sequence to call when the chord is played.
+    pub on: Vec<Event>,
+
+    /// A sequence to call when the chord is released.
+    pub off: Vec<Event>,
+}
+
+impl ChordMapping {
+    pub fn new(notes: &[MidiNote], channel: u8, window: u64) -> ChordMapping {
+        let mut notes = notes.to_vec();
+        notes.sort_by_key(|note| *note as u8);
+        notes.dedup();
+        ChordMapping {
+            notes,
+            channel,
+            window,
+            on: vec![],
+            off: vec![],
+        }
+    }
+
+    pub fn notes(&self) -> &[MidiNote] {
+        &self.notes
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+}
+
+/// A named set of mappings.  Only one layer is active at a time, and it can
+/// be switched at runtime with a Program Change or an `Event::SelectLayer`.
+#[derive(Clone, Debug, Default)]
+pub struct Layer {
+    /// The name used to select this layer.
+    name: String,
+
+    /// The Program Change number that selects this layer, if any.
+    program: Option<u8>,
+
     mappings: Vec<NoteMapping>,
+    controls: Vec<ControlMapping>,
+    continuous: Vec<ContinuousMapping>,
+    transport: Vec<TransportMapping>,
+    chords: Vec<ChordMapping>,
+}
+
+impl Layer {
+    pub fn new(name: &str, program: Option<u8>) -> Layer {
+        Layer {
+            name: name.to_owned(),
+            program,
+            ..Default::default()
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn program(&self) -> Option<u8> {
+        self.program
+    }
+
+    pub fn mappings(&self) -> &[NoteMapping] {
+        &self.mappings
+    }
+
+    pub fn controls(&self) -> &[ControlMapping] {
+        &self.controls
+    }
+
+    pub fn continuous(&self) -> &[ContinuousMapping] {
+        &self.continuous
+    }
+
+    pub fn transport(&self) -> &[TransportMapping] {
+        &self.transport
+    }
+
+    pub fn chords(&self) -> &[ChordMapping] {
+        &self.chords
+    }
+
+    /// The most specific mapping for a note, preferring the first one added
+    /// when there's a tie.
+    fn find(
+        &self,
+        note: MidiNote,
+        channel: u8,
+        instrument_name: Option<&str>,
+    ) -> Option<&NoteMapping> {
+        self.mappings
+            .iter()
+            .rev()
+            .filter(|mapping| mapping.matches(note, channel, instrument_name))
+            .max_by_key(|mapping| mapping.specificity())
+    }
+
+    fn find_control(&self, controller: u8, channel: u8) -> Option<&ControlMapping> {
+        self.controls
+            .iter()
+            .find(|mapping| mapping.controller == controller && mapping.channel == channel)
+    }
+
+    fn find_transport(&self, transport: Transport) -> Option<&TransportMapping> {
+        self.transport
+            .iter()
+            .find(|mapping| mapping.transport == transport)
+    }
+}
+
+/// The name of the layer that is always present, and which is searched
+/// whenever the active layer has no mapping for an input.
+pub const DEFAULT_LAYER: &str = "default";
+
+pub struct NoteMappings {
+    /// All layers.  The first one is the default layer.
+    layers: Vec<Layer>,
+
+    /// The index of the currently-active layer.
+    active: usize,
+}
+
+impl Default for NoteMappings {
+    fn default() -> NoteMappings {
+        NoteMappings {
+            layers: vec![Layer::new(DEFAULT_LAYER, None)],
+            active: 0,
+        }
+    }
 }
 
 impl NoteMappings {
@@ -198,56 +1107,188 @@ impl NoteMappings {
         NoteMappings::default()
     }
 
+    /// The active layer, followed by the default layer if that's different.
+    fn search_order(&self) -> Vec<&Layer> {
+        let mut order = vec![&self.layers[self.active]];
+        if self.active != 0 {
+            order.push(&self.layers[0]);
+        }
+        order
+    }
+
     /// Find a mapping for a given note, if one exists
     pub fn find(
         &self,
         note: MidiNote,
         channel: u8,
-        instrument_name: Option<String>,
+        instrument_name: Option<&str>,
     ) -> Option<NoteMapping> {
-        for mapping in &self.mappings {
-            if mapping.note == note
-                && mapping.channel == channel
-                && mapping.instrument_name == instrument_name
-            {
-                return Some(mapping.clone());
+        self.search_order()
+            .into_iter()
+            .find_map(|layer| layer.find(note, channel, instrument_name))
+            .cloned()
+    }
+
+    /// Find a mapping for a given controller, if one exists
+    pub fn find_control(&self, controller: u8, channel: u8) -> Option<ControlMapping> {
+        self.search_order()
+            .into_iter()
+            .find_map(|layer| layer.find_control(controller, channel))
+            .cloned()
+    }
+
+    /// Find a mapping for a given transport message, if one exists
+    pub fn find_transport(&self, transport: Transport) -> Option<TransportMapping> {
+        self.search_order()
+            .into_iter()
+            .find_map(|layer| layer.find_transport(transport))
+            .cloned()
+    }
+
+    /// All mappings for continuous inputs such as the pitch wheel
+    pub fn continuous(&self) -> Vec<ContinuousMapping> {
+        self.search_order()
+            .into_iter()
+            .flat_map(|layer| layer.continuous.iter().cloned())
+            .collect()
+    }
+
+    /// All chord mappings for a channel, from the active layer first
+    pub fn chords(&self, channel: u8) -> Vec<ChordMapping> {
+        self.search_order()
+            .into_iter()
+            .flat_map(|layer| layer.chords.iter())
+            .filter(|chord| chord.channel == channel)
+            .cloned()
+            .collect()
+    }
+
+    /// The name of the currently-active layer
+    pub fn active_layer(&self) -> &str {
+        self.layers[self.active].name()
+    }
+
+    /// Make the named layer active.
+    /// Returns `false` if there is no such layer.
+    pub fn select_layer(&mut self, name: &str) -> bool {
+        match self.layers.iter().position(|layer| layer.name == name) {
+            Some(idx) => {
+                self.active = idx;
+                true
             }
+            None => false,
         }
-        None
     }
 
-    pub fn import(&mut self, filename: &str) -> Result<()> {
+    /// Make the layer bound to a Program Change number active.  Programs
+    /// with no layer bound to them return to the default layer.
+    pub fn select_program(&mut self, program: u8) {
+        self.active = self
+            .layers
+            .iter()
+            .position(|layer| layer.program == Some(program))
+            .unwrap_or(0);
+    }
+
+    /// All layers, starting with the default layer
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// Load mappings from a file, adding them to the current set.
+    /// See `mappingfile` for the format.
+    pub fn import(&mut self, filename: &str) -> Result<(), MappingsError> {
         let f = File::open(filename)?;
-        let buf_reader = BufReader::new(f);
-        for line in buf_reader.lines() {
-            let l = line.unwrap();
-            let fields: Vec<&str> = l.split(' ').collect();
-            if fields.len() != 4 {
-                println!("Line is not 4 elements!");
-                continue;
-            }
-            let note_txt = fields[0];
-            let channel_txt = fields[1];
-            let keydown_txt = fields[2];
-            let keyup_txt = fields[3];
+        mappingfile::parse(self, filename, BufReader::new(f))
+    }
 
-            let note = MidiNote::new_from_text(&note_txt).unwrap();
-            let channel = channel_txt.parse::<u8>().unwrap();
-            let keydown = keydown_txt.chars().next().unwrap();
-            let keyup = keyup_txt.chars().next().unwrap();
+    /// Add a new layer.  Mappings added after this go into the new layer.
+    pub fn add_layer(&mut self, layer: Layer) {
+        self.layers.push(layer);
+    }
 
-            let mut mapping = NoteMapping::new(note, channel, None);
-            mapping.on = NoteMapping::down_event(keydown, None, None);
-            mapping.off = NoteMapping::up_event(keyup, None, None);
+    fn last_layer(&mut self) -> &mut Layer {
+        self.layers.last_mut().unwrap()
+    }
 
-            println!("Got line: {}  Mapping: {:?}", l, mapping);
-            self.add(mapping);
-        }
-        Ok(())
+    /// The mapping for exactly these notes, channel and instrument in the
+    /// most recently added layer, if any.
+    pub fn last_mapping_mut(
+        &mut self,
+        note: MidiNote,
+        last_note: MidiNote,
+        channel: Option<u8>,
+        instrument_name: Option<&str>,
+    ) -> Option<&mut NoteMapping> {
+        self.last_layer().mappings.iter_mut().find(|mapping| {
+            mapping.note == note
+                && mapping.last_note == last_note
+                && mapping.channel == channel
+                && mapping.instrument_name.as_deref() == instrument_name
+        })
     }
 
     pub fn add(&mut self, mapping: NoteMapping) {
         //Note: We need to remove old mappings here, too!
-        self.mappings.push(mapping);
+        self.last_layer().mappings.push(mapping);
+    }
+
+    pub fn add_control(&mut self, mapping: ControlMapping) {
+        self.last_layer().controls.push(mapping);
+    }
+
+    pub fn add_continuous(&mut self, mapping: ContinuousMapping) {
+        self.last_layer().continuous.push(mapping);
+    }
+
+    pub fn add_transport(&mut self, mapping: TransportMapping) {
+        self.last_layer().transport.push(mapping);
+    }
+
+    pub fn add_chord(&mut self, mapping: ChordMapping) {
+        self.last_layer().chords.push(mapping);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_patterns() {
+        let cases = [
+            ("Launchkey MK3", "Launchkey MK3", true),
+            ("Launchkey MK3", "Launchkey MK2", false),
+            ("Launchkey*", "Launchkey MK3 MIDI 1", true),
+            ("Launchkey*", "Launchkey", true),
+            ("Launchkey*", "Keystation", false),
+            ("*MIDI 1", "Launchkey MIDI 1", true),
+            ("*MIDI 1", "Launchkey MIDI 2", false),
+            ("*", "", true),
+            ("**", "anything", true),
+            ("", "", true),
+            ("", "a", false),
+            ("MK?", "MK3", true),
+            ("MK?", "MK", false),
+            ("MK?", "MK30", false),
+            ("?*?", "ab", true),
+            ("?*?", "a", false),
+            ("a*b*c", "axbxc", true),
+            ("a*b*c", "abc", true),
+            ("a*b*c", "axbxcxb", false),
+            ("a*b*c", "abxcbc", true),
+            ("a*b", "abab", true),
+            ("*ab", "aab", true),
+            ("café*", "café MIDI", true),
+        ];
+        for &(pattern, name, expected) in &cases {
+            assert_eq!(
+                name_matches(pattern, name),
+                expected,
+                "{} vs {}",
+                pattern,
+                name
+            );
+        }
     }
 }
@@ -0,0 +1,464 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::appstate::{AppState, KeyGen};
+use crate::midi::MidiNote;
+use crate::notemappings::{Event, KbdKey, Transport};
+
+/// A small delay required when switching between octaves.
+const OCTAVE_DELAY_MS: u64 = 10;
+
+/// Where a sequence came from.  Sequences from the same source run one
+/// after another, so a note's release never overtakes its press.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub enum Source {
+    /// A note, by device and channel
+    Note(String, u8, MidiNote),
+    /// A controller, by channel
+    Control(u8, u8),
+    Transport(Transport),
+    /// A chord, by device, channel and lowest note
+    Chord(String, u8, MidiNote),
+}
+
+/// A sequence waiting to run, or partway through running.
+struct Pending {
+    events: Vec<Event>,
+    position: usize,
+    /// The device whose message queued this sequence
+    device: String,
+    /// How long keys pressed by this sequence may be held
+    max_hold: Option<Duration>,
+    /// Set when the sequence should stop at its next step
+    cancelled: bool,
+}
+
+/// When a source's current sequence should next run.
+struct Due {
+    at: Instant,
+    order: u64,
+    source: Source,
+}
+
+impl PartialEq for Due {
+    fn eq(&self, other: &Due) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Due {}
+
+impl PartialOrd for Due {
+    fn partial_cmp(&self, other: &Due) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Due {
+    /// Reversed, so the earliest entry is at the top of the heap.  Entries
+    /// due at the same time run in the order they were added.
+    fn cmp(&self, other: &Due) -> Ordering {
+        (other.at, other.order).cmp(&(self.at, self.order))
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    /// One entry for each source with a sequence to run
+    due: BinaryHeap<Due>,
+    /// The sequences for each source.  The front one is running, and a
+    /// source is removed once it has nothing left to run.
+    sources: HashMap<Source, VecDeque<Pending>>,
+    order: u64,
+}
+
+impl Queue {
+    fn wake(&mut self, source: Source, at: Instant) {
+        self.order += 1;
+        self.due.push(Due {
+            at,
+            order: self.order,
+            source,
+        });
+    }
+}
+
+/// Runs mapping sequences on a thread of its own, so delays within a
+/// sequence don't hold up incoming MIDI messages.
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+}
+
+impl Scheduler {
+    /// Queue a sequence to run once any earlier ones from `source` finish.
+    /// Keys it presses are remembered as having come from `device`, and are
+    /// released if they're held for longer than `max_hold`.
+    pub fn schedule(
+        &self,
+        source: Source,
+        device: &str,
+        events: &[Event],
+        max_hold: Option<Duration>,
+    ) {
+        if events.is_empty() {
+            return;
+        }
+        let (ref queue, ref wakeup) = *self.queue;
+        let mut queue = queue.lock().unwrap();
+        let pending = queue
+            .sources
+            .entry(source.clone())
+            .or_insert_with(VecDeque::new);
+        pending.push_back(Pending {
+            events: events.to_vec(),
+            position: 0,
+            device: device.to_owned(),
+            max_hold,
+            cancelled: false,
+        });
+        if pending.len() == 1 {
+            queue.wake(source, Instant::now());
+            wakeup.notify_one();
+        }
+    }
+
+    /// Drop every sequence that hasn't finished running.
+    pub fn clear(&self) {
+        self.cancel(|_, _| true);
+    }
+
+    /// Drop every sequence that hasn't finished running, except those from
+    /// `source`.
+    pub fn clear_others(&self, source: &Source) {
+        self.cancel(|from, _| from != source);
+    }
+
+    /// Drop every sequence queued by `device` that hasn't finished running.
+    pub fn clear_device(&self, device: &str) {
+        self.cancel(|_, pending| pending.device == device);
+    }
+
+    /// Sequences that have started are only marked as cancelled, since
+    /// they may be running right now.  Their source is freed up once the
+    /// scheduler next gets to them.
+    fn cancel<F: Fn(&Source, &Pending) -> bool>(&self, matches: F) {
+        let mut queue = self.queue.0.lock().unwrap();
+        for (source, pending) in queue.sources.iter_mut() {
+            let mut front = pending.pop_front().unwrap();
+            pending.retain(|p| !matches(source, p));
+            if matches(source, &front) {
+                front.cancelled = true;
+            }
+            pending.push_front(front);
+        }
+    }
+
+    /// Run sequences as they come due.  This never returns.
+    pub fn run(&self, app_state: &AppState) {
+        let (ref queue, ref wakeup) = *self.queue;
+        let mut queue = queue.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let at = match queue.due.peek() {
+                Some(due) => due.at,
+                None => {
+                    queue = wakeup.wait(queue).unwrap();
+                    continue;
+                }
+            };
+            if at > now {
+                queue = wakeup.wait_timeout(queue, at - now).unwrap().0;
+                continue;
+            }
+
+            // Take the events out while they run, leaving the (empty)
+            // sequence at the front so nothing else from this source starts.
+            let source = queue.due.pop().unwrap().source;
+            let front = &mut queue.sources.get_mut(&source).unwrap()[0];
+            let wait = if front.cancelled {
+                None
+            } else {
+                let mut running = Pending {
+                    events: mem::take(&mut front.events),
+                    position: front.position,
+                    device: front.device.clone(),
+                    max_hold: front.max_hold,
+                    cancelled: false,
+                };
+                drop(queue);
+                let wait = run_events(app_state, &source, &mut running);
+                queue = self.queue.0.lock().unwrap();
+
+                let front = &mut queue.sources.get_mut(&source).unwrap()[0];
+                front.events = running.events;
+                front.position = running.position;
+                if front.cancelled {
+                    None
+                } else {
+                    wait
+                }
+            };
+
+            let pending = queue.sources.get_mut(&source).unwrap();
+            match wait {
+                Some(wait) => {
+                    queue.wake(source, Instant::now() + wait);
+                }
+                None => {
+                    pending.pop_front();
+                    if pending.is_empty() {
+                        queue.sources.remove(&source);
+                    } else {
+                        queue.wake(source, Instant::now());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Run a sequence from `source` until it needs to wait.  Returns how long
+/// to wait before running the rest, or `None` once the sequence is finished.
+fn run_events(app_state: &AppState, source: &Source, pending: &mut Pending) -> Option<Duration> {
+    let mut keygen = app_state.keygen().lock().unwrap();
+    while let Some(event) = pending.events.get(pending.position) {
+        pending.position += 1;
+        match *event {
+            Event::Delay(msecs) => return Some(Duration::from_millis(msecs)),
+            Event::KeyDown(ref k) => {
+                keygen.key_down_from(k, &pending.device, pending.max_hold);
+            }
+            Event::KeyUp(ref k) => {
+                keygen.key_up(k);
+            }
+            Event::Text(ref text) => {
+                keygen.text(text);
+            }
+            Event::MouseDown(button) => {
+                keygen.mouse_down_from(button, &pending.device);
+            }
+            Event::MouseUp(button) => {
+                keygen.mouse_up(button);
+            }
+            Event::MouseMove(x, y) => keygen.mouse_move(x, y),
+            Event::MouseMoveTo(x, y) => keygen.mouse_move_to(x, y),
+            Event::Scroll(x, y) => keygen.scroll(x, y),
+
+            // For NoteMod, which goes at the top of a note, see if we need to change
+            // the current set of modifiers.  If so, pause a short while.
+            // This enables fast switching between notes in the same octave, where no
+            // keychange is required.  While latched, notes that want the latched
+            // modifier or none keep it, so there's nothing to change.
+            Event::NoteMod(ref kopt) => {
+                let kopt = app_state.latch().lock().unwrap().note_mod(kopt);
+                if set_note_mod(&mut keygen, &kopt, &pending.device) > 0 {
+                    return Some(Duration::from_millis(OCTAVE_DELAY_MS));
+                }
+            }
+            Event::Latch(ref kopt) => {
+                app_state
+                    .latch()
+                    .lock()
+                    .unwrap()
+                    .latch(&pending.device, kopt.clone());
+                match *kopt {
+                    Some(ref k) => {
+                        println!("Latched {}", k);
+                        if set_note_mod(&mut keygen, kopt, &pending.device) > 0 {
+                            return Some(Duration::from_millis(OCTAVE_DELAY_MS));
+                        }
+                    }
+                    None => println!("Latched the next note's modifier"),
+                }
+            }
+            Event::Unlatch => {
+                if app_state.latch().lock().unwrap().unlatch(&pending.device) {
+                    set_note_mod(&mut keygen, &None, &pending.device);
+                }
+                println!("Unlatched");
+            }
+
+            Event::SelectLayer(ref name) => {
+                if app_state.mappings().lock().unwrap().select_layer(name) {
+                    let released = app_state.reset_notes(&mut keygen, Some(source));
+                    println!("Switched to layer {} ({} keys released)", name, released);
+                } else {
+                    println!("No layer named {}", name);
+                }
+            }
+
+            Event::Transpose(semitones) => {
+                let offset = app_state.transposer().lock().unwrap().shift(semitones);
+                println!("Transposed by {} semitones", offset);
+            }
+            Event::TransposeReset => {
+                app_state.transposer().lock().unwrap().reset();
+                println!("Transposed by 0 semitones");
+            }
+        }
+    }
+    None
+}
+
+/// Hold down `kopt`, if it's a modifier, and release the other modifiers.
+/// Returns the number of keys pressed or released.
+fn set_note_mod(keygen: &mut KeyGen, kopt: &Option<KbdKey>, device: &str) -> u32 {
+    let mut changes = 0;
+    let key_mods = [KbdKey::Shift, KbdKey::Control];
+    if let Some(ref k) = *kopt {
+        for key_mod in &key_mods {
+            if key_mod == k {
+                if keygen.key_down_from(key_mod, device, None) {
+                    changes += 1;
+                }
+            } else if keygen.key_up(key_mod) {
+                changes += 1;
+            }
+        }
+    } else {
+        for key_mod in &key_mods {
+            if keygen.key_up(key_mod) {
+                changes += 1;
+            }
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{KeyAction, RecordingBackend};
+    use crate::notemappings::Layer;
+
+    const SOURCE: Source = Source::Control(0, 64);
+
+    fn pending(events: Vec<Event>) -> Pending {
+        Pending {
+            events,
+            position: 0,
+            device: "kbd".to_owned(),
+            max_hold: None,
+            cancelled: false,
+        }
+    }
+
+    #[test]
+    fn run_events_stops_at_delays() {
+        let backend = RecordingBackend::new();
+        let app_state = AppState::with_backend(Box::new(backend.clone()));
+        let a = KbdKey::Layout('a');
+        let mut running = pending(vec![
+            Event::KeyDown(a.clone()),
+            Event::Delay(40),
+            Event::KeyUp(a.clone()),
+            Event::Text("hi".to_owned()),
+        ]);
+
+        assert_eq!(
+            run_events(&app_state, &SOURCE, &mut running),
+            Some(Duration::from_millis(40))
+        );
+        assert_eq!(backend.actions(), vec![KeyAction::Down(a.clone())]);
+        assert_eq!(run_events(&app_state, &SOURCE, &mut running), None);
+        assert_eq!(
+            backend.actions(),
+            vec![
+                KeyAction::Down(a.clone()),
+                KeyAction::Up(a),
+                KeyAction::Text("hi".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn note_mod_waits_only_when_modifiers_change() {
+        let backend = RecordingBackend::new();
+        let app_state = AppState::with_backend(Box::new(backend.clone()));
+        let shifted = || {
+            pending(vec![
+                Event::NoteMod(Some(KbdKey::Shift)),
+                Event::KeyDown(KbdKey::Layout('a')),
+            ])
+        };
+
+        let mut first = shifted();
+        assert_eq!(
+            run_events(&app_state, &SOURCE, &mut first),
+            Some(Duration::from_millis(OCTAVE_DELAY_MS))
+        );
+        assert_eq!(run_events(&app_state, &SOURCE, &mut first), None);
+        let mut second = shifted();
+        assert_eq!(run_events(&app_state, &SOURCE, &mut second), None);
+
+        let mut plain = pending(vec![Event::NoteMod(None)]);
+        assert_eq!(
+            run_events(&app_state, &SOURCE, &mut plain),
+            Some(Duration::from_millis(OCTAVE_DELAY_MS))
+        );
+        assert_eq!(
+            backend.actions().last(),
+            Some(&KeyAction::Up(KbdKey::Shift))
+        );
+    }
+
+    #[test]
+    fn select_layer_resets_everything_but_its_own_source() {
+        let backend = RecordingBackend::new();
+        let app_state = AppState::with_backend(Box::new(backend.clone()));
+        app_state
+            .mappings()
+            .lock()
+            .unwrap()
+            .add_layer(Layer::new("nav", None));
+        let a = KbdKey::Layout('a');
+        let other = Source::Control(0, 1);
+        let events = vec![Event::KeyDown(a.clone())];
+
+        let mut press = pending(events.clone());
+        assert_eq!(run_events(&app_state, &other, &mut press), None);
+        app_state
+            .velocities()
+            .lock()
+            .unwrap()
+            .insert(("kbd".to_owned(), 0, MidiNote::C4), 100);
+        app_state
+            .latch()
+            .lock()
+            .unwrap()
+            .latch("kbd", Some(KbdKey::Shift));
+        let scheduler = app_state.scheduler();
+        scheduler.schedule(other.clone(), "kbd", &events, None);
+        scheduler.schedule(SOURCE, "kbd", &events, None);
+
+        let mut switch = pending(vec![Event::SelectLayer("nav".to_owned())]);
+        assert_eq!(run_events(&app_state, &SOURCE, &mut switch), None);
+        assert_eq!(app_state.mappings().lock().unwrap().active_layer(), "nav");
+        assert_eq!(backend.actions().last(), Some(&KeyAction::Up(a)));
+        assert!(app_state.velocities().lock().unwrap().is_empty());
+        assert!(!app_state.latch().lock().unwrap().unlatch("kbd"));
+        let queue = scheduler.queue.0.lock().unwrap();
+        assert!(queue.sources[&other][0].cancelled);
+        assert!(!queue.sources[&SOURCE][0].cancelled);
+    }
+
+    #[test]
+    fn devices_queue_the_same_note_separately() {
+        let scheduler = Scheduler::default();
+        let events = vec![Event::Delay(100)];
+        for device in &["a", "b", "a"] {
+            let source = Source::Note(device.to_string(), 0, MidiNote::C4);
+            scheduler.schedule(source, device, &events, None);
+        }
+        let queue = scheduler.queue.0.lock().unwrap();
+        let queued =
+            |device: &str| queue.sources[&Source::Note(device.to_owned(), 0, MidiNote::C4)].len();
+        assert_eq!(queued("a"), 2);
+        assert_eq!(queued("b"), 1);
+        assert_eq!(queue.due.len(), 2);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use crate::midi::MidiNote;
+
+/// The channel drum pads and percussion use by convention (channel 10, when
+/// counting from 1).  Its notes pick out sounds rather than pitches, so
+/// they're never transposed.
+pub const DRUM_CHANNEL: u8 = 9;
+
+/// The furthest notes can be shifted in either direction.
+pub const MAX_TRANSPOSE: i8 = 48;
+
+/// Shifts incoming notes by a number of semitones before they're looked up.
+///
+/// Each held note remembers the note it was shifted to, so releasing it
+/// still matches its press even if the offset changed in between.
+#[derive(Default)]
+pub struct Transposer {
+    offset: i8,
+    held: HashMap<(u8, MidiNote), MidiNote>,
+}
+
+impl Transposer {
+    pub fn new() -> Transposer {
+        Transposer::default()
+    }
+
+    /// The current offset, in semitones.
+    pub fn offset(&self) -> i8 {
+        self.offset
+    }
+
+    /// Move the offset by `semitones`, staying within `MAX_TRANSPOSE`.
+    /// Returns the new offset.
+    pub fn shift(&mut self, semitones: i8) -> i8 {
+        let offset = i16::from(self.offset) + i16::from(semitones);
+        let max = i16::from(MAX_TRANSPOSE);
+        self.offset = offset.max(-max).min(max) as i8;
+        self.offset
+    }
+
+    /// Go back to playing notes as they are.
+    pub fn reset(&mut self) {
+        self.offset = 0;
+    }
+
+    /// Shift a note that was just pressed.  Returns `None` if it lands
+    /// outside the MIDI note range.
+    pub fn note_on(&mut self, channel: u8, note: MidiNote) -> Option<MidiNote> {
+        if channel == DRUM_CHANNEL {
+            return Some(note);
+        }
+        let shifted = note.checked_add(self.offset);
+        match shifted {
+            Some(shifted) => self.held.insert((channel, note), shifted),
+            None => self.held.remove(&(channel, note)),
+        };
+        shifted
+    }
+
+    /// Shift a note that was just released, the same way it was shifted
+    /// when it was pressed.
+    pub fn note_off(&mut self, channel: u8, note: MidiNote) -> Option<MidiNote> {
+        if channel == DRUM_CHANNEL {
+            return Some(note);
+        }
+        match self.held.remove(&(channel, note)) {
+            Some(shifted) => Some(shifted),
+            None => note.checked_add(self.offset),
+        }
+    }
+
+    /// Forget which notes are held.
+    pub fn clear(&mut self) {
+        self.held.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shift_is_clamped() {
+        let mut transposer = Transposer::new();
+        assert_eq!(transposer.shift(12), 12);
+        assert_eq!(transposer.shift(36), MAX_TRANSPOSE);
+        assert_eq!(transposer.shift(12), MAX_TRANSPOSE);
+        assert_eq!(transposer.shift(i8::MIN), -MAX_TRANSPOSE);
+        assert_eq!(transposer.shift(-1), -MAX_TRANSPOSE);
+        assert_eq!(transposer.shift(i8::MAX), MAX_TRANSPOSE);
+        transposer.reset();
+        assert_eq!(transposer.offset(), 0);
+    }
+
+    #[test]
+    fn notes_past_the_edges_are_dropped() {
+        let mut transposer = Transposer::new();
+        transposer.shift(-12);
+        assert_eq!(transposer.note_on(0, MidiNote::C4), Some(MidiNote::C3));
+        assert_eq!(transposer.note_on(0, MidiNote::Bn), None);
+        assert_eq!(transposer.note_on(0, MidiNote::C0), Some(MidiNote::Cn));
+
+        transposer.shift(24);
+        assert_eq!(transposer.note_on(0, MidiNote::G8), Some(MidiNote::G9));
+        assert_eq!(transposer.note_on(0, MidiNote::Gs8), None);
+    }
+
+    #[test]
+    fn release_matches_press() {
+        let mut transposer = Transposer::new();
+        transposer.shift(12);
+        assert_eq!(transposer.note_on(0, MidiNote::C4), Some(MidiNote::C5));
+        transposer.reset();
+        assert_eq!(transposer.note_off(0, MidiNote::C4), Some(MidiNote::C5));
+        // Without a press to match, the current offset is used.
+        assert_eq!(transposer.note_off(0, MidiNote::C4), Some(MidiNote::C4));
+    }
+
+    #[test]
+    fn drums_are_not_shifted() {
+        let mut transposer = Transposer::new();
+        transposer.shift(12);
+        assert_eq!(
+            transposer.note_on(DRUM_CHANNEL, MidiNote::C4),
+            Some(MidiNote::C4)
+        );
+        assert_eq!(
+            transposer.note_off(DRUM_CHANNEL, MidiNote::C4),
+            Some(MidiNote::C4)
+        );
+    }
+}
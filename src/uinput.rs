@@ -0,0 +1,576 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::slice;
+
+use crate::backend::KeyBackend;
+use crate::notemappings::{KbdKey, MouseButton};
+
+// Constants from linux/input-event-codes.h and linux/uinput.h
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const SYN_REPORT: u16 = 0;
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+const BTN_LEFT: u16 = 0x110;
+const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const BUS_VIRTUAL: u16 = 0x06;
+const UI_SET_EVBIT: u64 = 0x4004_5564;
+const UI_SET_KEYBIT: u64 = 0x4004_5565;
+const UI_SET_RELBIT: u64 = 0x4004_5566;
+const UI_DEV_CREATE: u64 = 0x5501;
+const UINPUT_MAX_NAME_SIZE: usize = 80;
+const ABS_CNT: usize = 64;
+
+/// The highest evdev key code.
+const KEY_MAX: u16 = 0x2ff;
+
+const KEY_ESC: u16 = 1;
+const KEY_MINUS: u16 = 12;
+const KEY_EQUAL: u16 = 13;
+const KEY_BACKSPACE: u16 = 14;
+const KEY_TAB: u16 = 15;
+const KEY_LEFTBRACE: u16 = 26;
+const KEY_RIGHTBRACE: u16 = 27;
+const KEY_ENTER: u16 = 28;
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_SEMICOLON: u16 = 39;
+const KEY_APOSTROPHE: u16 = 40;
+const KEY_GRAVE: u16 = 41;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_BACKSLASH: u16 = 43;
+const KEY_COMMA: u16 = 51;
+const KEY_DOT: u16 = 52;
+const KEY_SLASH: u16 = 53;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_LEFTALT: u16 = 56;
+const KEY_SPACE: u16 = 57;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_F1: u16 = 59;
+const KEY_F11: u16 = 87;
+const KEY_F12: u16 = 88;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_HOME: u16 = 102;
+const KEY_UP: u16 = 103;
+const KEY_PAGEUP: u16 = 104;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_DOWN: u16 = 108;
+const KEY_PAGEDOWN: u16 = 109;
+const KEY_LEFTMETA: u16 = 125;
+
+/// Whether an evdev code is a key, rather than one of the mouse, joystick
+/// and gamepad buttons that share the same range.  Only keys are enabled,
+/// since buttons would make the device look like something other than a
+/// keyboard.
+fn is_key(code: u16) -> bool {
+    matches!(code, 1..=0xff | 0x160..=0x21f | 0x224..=0x2bf | 0x2e8..=KEY_MAX)
+}
+
+/// Letter keycodes, in alphabetical order
+const LETTERS: [u16; 26] = [
+    30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45,
+    21, 44,
+];
+
+/// Digit keycodes, from 0 to 9
+const DIGITS: [u16; 10] = [11, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
+/// The evdev keycode for a key, and whether Shift is needed to type it.
+/// Layout keys assume a US keyboard layout, and raw keys are taken to
+/// already be evdev keycodes (`KEY_*` from linux/input-event-codes.h).
+pub fn key_code(key: &KbdKey) -> Option<(u16, bool)> {
+    let code = match *key {
+        KbdKey::Return => KEY_ENTER,
+        KbdKey::Tab => KEY_TAB,
+        KbdKey::Space => KEY_SPACE,
+        KbdKey::Backspace => KEY_BACKSPACE,
+        KbdKey::Escape => KEY_ESC,
+        KbdKey::Meta => KEY_LEFTMETA,
+        KbdKey::Shift => KEY_LEFTSHIFT,
+        KbdKey::CapsLock => KEY_CAPSLOCK,
+        KbdKey::Alt | KbdKey::Option => KEY_LEFTALT,
+        KbdKey::Control => KEY_LEFTCTRL,
+        KbdKey::Home => KEY_HOME,
+        KbdKey::PageUp => KEY_PAGEUP,
+        KbdKey::PageDown => KEY_PAGEDOWN,
+        KbdKey::LeftArrow => KEY_LEFT,
+        KbdKey::RightArrow => KEY_RIGHT,
+        KbdKey::DownArrow => KEY_DOWN,
+        KbdKey::UpArrow => KEY_UP,
+        KbdKey::F1 => KEY_F1,
+        KbdKey::F2 => KEY_F1 + 1,
+        KbdKey::F3 => KEY_F1 + 2,
+        KbdKey::F4 => KEY_F1 + 3,
+        KbdKey::F5 => KEY_F1 + 4,
+        KbdKey::F6 => KEY_F1 + 5,
+        KbdKey::F7 => KEY_F1 + 6,
+        KbdKey::F8 => KEY_F1 + 7,
+        KbdKey::F9 => KEY_F1 + 8,
+        KbdKey::F10 => KEY_F1 + 9,
+        KbdKey::F11 => KEY_F11,
+        KbdKey::F12 => KEY_F12,
+        KbdKey::Layout(c) => return layout_code(c),
+        KbdKey::Raw(code) if is_key(code) => code,
+        KbdKey::Raw(_) => return None,
+    };
+    Some((code, false))
+}
+
+/// The keycode for a character on a US keyboard, and whether it needs Shift.
+fn layout_code(c: char) -> Option<(u16, bool)> {
+    if c.is_ascii_lowercase() {
+        return Some((LETTERS[(c as u8 - b'a') as usize], false));
+    }
+    if c.is_ascii_uppercase() {
+        return Some((LETTERS[(c as u8 - b'A') as usize], true));
+    }
+    if c.is_ascii_digit() {
+        return Some((DIGITS[(c as u8 - b'0') as usize], false));
+    }
+    let code = match c {
+        ' ' => (KEY_SPACE, false),
+        '\n' => (KEY_ENTER, false),
+        '\t' => (KEY_TAB, false),
+        '-' => (KEY_MINUS, false),
+        '_' => (KEY_MINUS, true),
+        '=' => (KEY_EQUAL, false),
+        '+' => (KEY_EQUAL, true),
+        '[' => (KEY_LEFTBRACE, false),
+        '{' => (KEY_LEFTBRACE, true),
+        ']' => (KEY_RIGHTBRACE, false),
+        '}' => (KEY_RIGHTBRACE, true),
+        '\\' => (KEY_BACKSLASH, false),
+        '|' => (KEY_BACKSLASH, true),
+        ';' => (KEY_SEMICOLON, false),
+        ':' => (KEY_SEMICOLON, true),
+        '\'' => (KEY_APOSTROPHE, false),
+        '"' => (KEY_APOSTROPHE, true),
+        '`' => (KEY_GRAVE, false),
+        '~' => (KEY_GRAVE, true),
+        ',' => (KEY_COMMA, false),
+        '<' => (KEY_COMMA, true),
+        '.' => (KEY_DOT, false),
+        '>' => (KEY_DOT, true),
+        '/' => (KEY_SLASH, false),
+        '?' => (KEY_SLASH, true),
+        '!' => (DIGITS[1], true),
+        '@' => (DIGITS[2], true),
+        '#' => (DIGITS[3], true),
+        '$' => (DIGITS[4], true),
+        '%' => (DIGITS[5], true),
+        '^' => (DIGITS[6], true),
+        '&' => (DIGITS[7], true),
+        '*' => (DIGITS[8], true),
+        '(' => (DIGITS[9], true),
+        ')' => (DIGITS[0], true),
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Sends keys through a virtual keyboard created with the Linux uinput
+/// driver, which works without a display server.
+///
+/// Characters that need Shift are typed with the right Shift key, so they
+/// don't disturb a left Shift that a mapping is holding down.  Text that
+/// can't be typed on a US keyboard is entered with Ctrl+Shift+U and the
+/// character's hex code, which works in GTK and Qt applications using IBus.
+/// That sequence also uses the right-hand modifiers, for the same reason.
+///
+/// The mouse is a second virtual device, since a single device with both
+/// keys and buttons isn't always recognised as a keyboard.  It can only be
+/// moved by an amount, and not to a position on the screen.
+pub struct UinputBackend<D: Write = File> {
+    device: D,
+    mouse: D,
+}
+
+impl UinputBackend<File> {
+    /// Create a new virtual keyboard and mouse.  This usually requires
+    /// write access to /dev/uinput.
+    pub fn create() -> io::Result<UinputBackend<File>> {
+        let mut keys = vec![(UI_SET_EVBIT, EV_KEY)];
+        keys.extend(
+            (1..=KEY_MAX)
+                .filter(|&code| is_key(code))
+                .map(|code| (UI_SET_KEYBIT, code)),
+        );
+        let device = create_device(b"miditran virtual keyboard", &keys)?;
+
+        let mouse = create_device(
+            b"miditran virtual mouse",
+            &[
+                (UI_SET_EVBIT, EV_KEY),
+                (UI_SET_KEYBIT, BTN_LEFT),
+                (UI_SET_KEYBIT, BTN_RIGHT),
+                (UI_SET_KEYBIT, BTN_MIDDLE),
+                (UI_SET_EVBIT, EV_REL),
+                (UI_SET_RELBIT, REL_X),
+                (UI_SET_RELBIT, REL_Y),
+                (UI_SET_RELBIT, REL_WHEEL),
+                (UI_SET_RELBIT, REL_HWHEEL),
+            ],
+        )?;
+        Ok(UinputBackend { device, mouse })
+    }
+}
+
+/// Create a virtual device called `name`, enabling each (ioctl, code) pair
+/// in `bits`.
+fn create_device(name: &[u8], bits: &[(u64, u16)]) -> io::Result<File> {
+    let mut file = OpenOptions::new().write(true).open("/dev/uinput")?;
+    let fd = file.as_raw_fd();
+
+    for &(request, code) in bits {
+        ioctl(fd, request, libc::c_int::from(code))?;
+    }
+
+    // struct uinput_user_dev
+    let mut setup = vec![0u8; UINPUT_MAX_NAME_SIZE];
+    setup[..name.len()].copy_from_slice(name);
+    for id in &[BUS_VIRTUAL, 0, 0, 1] {
+        setup.extend_from_slice(&id.to_ne_bytes());
+    }
+    // ff_effects_max, followed by absmax, absmin, absfuzz and absflat
+    setup.resize(setup.len() + mem::size_of::<i32>() * (1 + ABS_CNT * 4), 0);
+    file.write_all(&setup)?;
+
+    ioctl(fd, UI_DEV_CREATE, 0)?;
+    Ok(file)
+}
+
+fn ioctl(fd: libc::c_int, request: u64, arg: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, request as _, arg) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Write a single `struct input_event`.  The kernel fills in the time.
+fn write_event<W: Write>(device: &mut W, kind: u16, code: u16, value: i32) -> io::Result<()> {
+    let event = libc::input_event {
+        time: libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        type_: kind,
+        code,
+        value,
+    };
+    let bytes = unsafe {
+        slice::from_raw_parts(
+            &event as *const libc::input_event as *const u8,
+            mem::size_of::<libc::input_event>(),
+        )
+    };
+    device.write_all(bytes)
+}
+
+impl<D: Write> UinputBackend<D> {
+    /// Send events to existing keyboard and mouse devices, or to any
+    /// writers such as plain files when testing.
+    pub fn with_device(device: D, mouse: D) -> UinputBackend<D> {
+        UinputBackend { device, mouse }
+    }
+
+    /// Write a single `struct input_event` to the keyboard.
+    fn emit(&mut self, kind: u16, code: u16, value: i32) -> io::Result<()> {
+        write_event(&mut self.device, kind, code, value)
+    }
+
+    /// Send mouse events, each a (type, code, value) triple, followed by a
+    /// sync.
+    fn emit_mouse(&mut self, events: &[(u16, u16, i32)]) -> io::Result<()> {
+        for &(kind, code, value) in events {
+            if value != 0 || kind == EV_KEY {
+                write_event(&mut self.mouse, kind, code, value)?;
+            }
+        }
+        write_event(&mut self.mouse, EV_SYN, SYN_REPORT, 0)?;
+        self.mouse.flush()
+    }
+
+    /// Press (`value` = 1) or release (`value` = 0) a key, followed by a
+    /// sync so the key takes effect immediately.
+    fn send(&mut self, key: &KbdKey, value: i32) -> io::Result<()> {
+        let (code, shift) = match key_code(key) {
+            Some(code) => code,
+            None => {
+                println!("No uinput keycode for {}", key);
+                return Ok(());
+            }
+        };
+        if shift && value == 1 {
+            self.emit(EV_KEY, KEY_RIGHTSHIFT, 1)?;
+        }
+        self.emit(EV_KEY, code, value)?;
+        if shift && value == 0 {
+            self.emit(EV_KEY, KEY_RIGHTSHIFT, 0)?;
+        }
+        self.emit(EV_SYN, SYN_REPORT, 0)?;
+        self.device.flush()
+    }
+
+    /// Press and release a key by its keycode, holding the right Shift key
+    /// if `shift` is set.
+    fn tap(&mut self, code: u16, shift: bool) -> io::Result<()> {
+        if shift {
+            self.emit(EV_KEY, KEY_RIGHTSHIFT, 1)?;
+        }
+        self.emit(EV_KEY, code, 1)?;
+        self.emit(EV_SYN, SYN_REPORT, 0)?;
+        self.emit(EV_KEY, code, 0)?;
+        if shift {
+            self.emit(EV_KEY, KEY_RIGHTSHIFT, 0)?;
+        }
+        self.emit(EV_SYN, SYN_REPORT, 0)
+    }
+
+    /// Type each character of `text` in turn.
+    fn type_text(&mut self, text: &str) -> io::Result<()> {
+        for c in text.chars() {
+            if let Some((code, shift)) = layout_code(c) {
+                self.tap(code, shift)?;
+                continue;
+            }
+
+            self.emit(EV_KEY, KEY_RIGHTCTRL, 1)?;
+            self.emit(EV_KEY, KEY_RIGHTSHIFT, 1)?;
+            self.tap(LETTERS[(b'u' - b'a') as usize], false)?;
+            self.emit(EV_KEY, KEY_RIGHTSHIFT, 0)?;
+            self.emit(EV_KEY, KEY_RIGHTCTRL, 0)?;
+            self.emit(EV_SYN, SYN_REPORT, 0)?;
+            for digit in format!("{:x}", c as u32).chars() {
+                let (code, _) = layout_code(digit).unwrap();
+                self.tap(code, false)?;
+            }
+            self.tap(KEY_SPACE, false)?;
+        }
+        self.device.flush()
+    }
+}
+
+impl<D: Write> KeyBackend for UinputBackend<D> {
+    fn key_down(&mut self, key: &KbdKey) {
+        if let Err(e) = self.send(key, 1) {
+            println!("Unable to press {}: {}", key, e);
+        }
+    }
+
+    fn key_up(&mut self, key: &KbdKey) {
+        if let Err(e) = self.send(key, 0) {
+            println!("Unable to release {}: {}", key, e);
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if let Err(e) = self.type_text(text) {
+            println!("Unable to type {:?}: {}", text, e);
+        }
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) {
+        if let Err(e) = self.emit_mouse(&[(EV_KEY, button_code(button), 1)]) {
+            println!("Unable to press the {} mouse button: {}", button, e);
+        }
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) {
+        if let Err(e) = self.emit_mouse(&[(EV_KEY, button_code(button), 0)]) {
+            println!("Unable to release the {} mouse button: {}", button, e);
+        }
+    }
+
+    fn mouse_move(&mut self, x: i32, y: i32) {
+        if let Err(e) = self.emit_mouse(&[(EV_REL, REL_X, x), (EV_REL, REL_Y, y)]) {
+            println!("Unable to move the mouse: {}", e);
+        }
+    }
+
+    fn mouse_move_to(&mut self, x: i32, y: i32) {
+        println!(
+            "Unable to move the mouse to {}, {}: uinput can only move it by an amount",
+            x, y
+        );
+    }
+
+    fn scroll(&mut self, x: i32, y: i32) {
+        // The wheel counts up as scrolling up, where `y` counts down.
+        if let Err(e) = self.emit_mouse(&[(EV_REL, REL_HWHEEL, x), (EV_REL, REL_WHEEL, -y)]) {
+            println!("Unable to scroll: {}", e);
+        }
+    }
+}
+
+fn button_code(button: MouseButton) -> u16 {
+    match button {
+        MouseButton::Left => BTN_LEFT,
+        MouseButton::Middle => BTN_MIDDLE,
+        MouseButton::Right => BTN_RIGHT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Split the bytes written to a device back into (type, code, value)
+    /// triples.
+    fn events(bytes: &[u8]) -> Vec<(u16, u16, i32)> {
+        let size = mem::size_of::<libc::input_event>();
+        assert_eq!(bytes.len() % size, 0);
+        bytes
+            .chunks(size)
+            .map(|chunk| {
+                let event =
+                    unsafe { (chunk.as_ptr() as *const libc::input_event).read_unaligned() };
+                (event.type_, event.code, event.value)
+            })
+            .collect()
+    }
+
+    fn backend() -> UinputBackend<Vec<u8>> {
+        UinputBackend::with_device(vec![], vec![])
+    }
+
+    const SYN: (u16, u16, i32) = (EV_SYN, SYN_REPORT, 0);
+
+    #[test]
+    fn key_down_and_up() {
+        let mut backend = backend();
+        backend.key_down(&KbdKey::Layout('a'));
+        backend.key_up(&KbdKey::Layout('a'));
+        assert_eq!(
+            events(&backend.device),
+            vec![(EV_KEY, 30, 1), SYN, (EV_KEY, 30, 0), SYN]
+        );
+        assert!(backend.mouse.is_empty());
+    }
+
+    #[test]
+    fn shifted_characters_use_right_shift() {
+        let mut backend = backend();
+        backend.key_down(&KbdKey::Layout('?'));
+        backend.key_up(&KbdKey::Layout('?'));
+        assert_eq!(
+            events(&backend.device),
+            vec![
+                (EV_KEY, KEY_RIGHTSHIFT, 1),
+                (EV_KEY, KEY_SLASH, 1),
+                SYN,
+                (EV_KEY, KEY_SLASH, 0),
+                (EV_KEY, KEY_RIGHTSHIFT, 0),
+                SYN,
+            ]
+        );
+    }
+
+    #[test]
+    fn unicode_entry_leaves_held_modifiers_alone() {
+        let mut backend = backend();
+        backend.key_down(&KbdKey::Shift);
+        backend.key_down(&KbdKey::Control);
+        backend.text("é");
+        let sent = events(&backend.device);
+        assert_eq!(
+            &sent[..6],
+            &[
+                (EV_KEY, KEY_LEFTSHIFT, 1),
+                SYN,
+                (EV_KEY, KEY_LEFTCTRL, 1),
+                SYN,
+                (EV_KEY, KEY_RIGHTCTRL, 1),
+                (EV_KEY, KEY_RIGHTSHIFT, 1),
+            ]
+        );
+        assert!(!sent.contains(&(EV_KEY, KEY_LEFTSHIFT, 0)));
+        assert!(!sent.contains(&(EV_KEY, KEY_LEFTCTRL, 0)));
+    }
+
+    #[test]
+    fn keys_without_a_code_send_nothing() {
+        let mut backend = backend();
+        backend.key_down(&KbdKey::Layout('é'));
+        backend.key_down(&KbdKey::Raw(BTN_LEFT));
+        assert!(backend.device.is_empty());
+    }
+
+    #[test]
+    fn mouse_events_go_to_the_mouse() {
+        let mut backend = backend();
+        backend.mouse_down(MouseButton::Right);
+        backend.mouse_move(5, 0);
+        backend.scroll(0, 2);
+        assert_eq!(
+            events(&backend.mouse),
+            vec![
+                (EV_KEY, BTN_RIGHT, 1),
+                SYN,
+                (EV_REL, REL_X, 5),
+                SYN,
+                (EV_REL, REL_WHEEL, -2),
+                SYN,
+            ]
+        );
+        assert!(backend.device.is_empty());
+    }
+
+    #[test]
+    fn key_codes() {
+        let table = [
+            (KbdKey::Return, Some((KEY_ENTER, false))),
+            (KbdKey::Tab, Some((KEY_TAB, false))),
+            (KbdKey::Space, Some((KEY_SPACE, false))),
+            (KbdKey::Backspace, Some((KEY_BACKSPACE, false))),
+            (KbdKey::Escape, Some((KEY_ESC, false))),
+            (KbdKey::Meta, Some((KEY_LEFTMETA, false))),
+            (KbdKey::Shift, Some((KEY_LEFTSHIFT, false))),
+            (KbdKey::CapsLock, Some((KEY_CAPSLOCK, false))),
+            (KbdKey::Alt, Some((KEY_LEFTALT, false))),
+            (KbdKey::Option, Some((KEY_LEFTALT, false))),
+            (KbdKey::Control, Some((KEY_LEFTCTRL, false))),
+            (KbdKey::Home, Some((KEY_HOME, false))),
+            (KbdKey::PageUp, Some((KEY_PAGEUP, false))),
+            (KbdKey::PageDown, Some((KEY_PAGEDOWN, false))),
+            (KbdKey::LeftArrow, Some((KEY_LEFT, false))),
+            (KbdKey::RightArrow, Some((KEY_RIGHT, false))),
+            (KbdKey::DownArrow, Some((KEY_DOWN, false))),
+            (KbdKey::UpArrow, Some((KEY_UP, false))),
+            (KbdKey::F1, Some((59, false))),
+            (KbdKey::F2, Some((60, false))),
+            (KbdKey::F3, Some((61, false))),
+            (KbdKey::F4, Some((62, false))),
+            (KbdKey::F5, Some((63, false))),
+            (KbdKey::F6, Some((64, false))),
+            (KbdKey::F7, Some((65, false))),
+            (KbdKey::F8, Some((66, false))),
+            (KbdKey::F9, Some((67, false))),
+            (KbdKey::F10, Some((68, false))),
+            (KbdKey::F11, Some((87, false))),
+            (KbdKey::F12, Some((88, false))),
+            (KbdKey::Layout('q'), Some((16, false))),
+            (KbdKey::Layout('Q'), Some((16, true))),
+            (KbdKey::Layout('0'), Some((11, false))),
+            (KbdKey::Layout(')'), Some((11, true))),
+            (KbdKey::Layout('\n'), Some((KEY_ENTER, false))),
+            (KbdKey::Layout('é'), None),
+            (KbdKey::Raw(0x38), Some((0x38, false))),
+            // KEY_MICMUTE, above the old 0xff limit
+            (KbdKey::Raw(0x248), Some((0x248, false))),
+            (KbdKey::Raw(0), None),
+            (KbdKey::Raw(BTN_MIDDLE), None),
+            (KbdKey::Raw(0x2c0), None),
+            (KbdKey::Raw(KEY_MAX + 1), None),
+        ];
+        for (key, code) in &table {
+            assert_eq!(key_code(key), *code, "{}", key);
+        }
+    }
+}